@@ -0,0 +1,121 @@
+use crate::dialogue::{Dialogue, Part};
+
+/// Directory in the prompt directory that the actions' prompts are in
+pub(crate) const ACTION_DIR: &str = "actions";
+
+/// A one-off generation about a message, run from the message's context menu; it uses its own
+/// prompt, and leaves the channel's dialogue alone
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Action {
+    Ask,
+    Summarize,
+    Explain,
+}
+
+/// Which other messages in the channel are read along with the chosen one
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum History {
+    None,
+    /// Up to this many messages before the chosen one
+    Before(u8),
+    /// Up to this many messages after the chosen one
+    After(u8),
+}
+
+impl Action {
+    /// Name of the action's prompt in the action directory
+    pub(crate) fn prompt_name(self) -> &'static str {
+        match self {
+            Action::Ask => "ask",
+            Action::Summarize => "summarize",
+            Action::Explain => "explain",
+        }
+    }
+
+    pub(crate) fn history(self) -> History {
+        match self {
+            Action::Ask => History::Before(10),
+            Action::Summarize => History::After(100),
+            Action::Explain => History::None,
+        }
+    }
+
+    /// Name of the thread that a long answer is put in
+    pub(crate) fn thread_name(self) -> &'static str {
+        match self {
+            Action::Ask => "Answer",
+            Action::Summarize => "Summary",
+            Action::Explain => "Explanation",
+        }
+    }
+}
+
+/// The dialogue for an action, with a user turn for each message as (author, text), oldest
+/// first; messages furthest from the chosen one are left out if they don't all fit in `max_len`,
+/// but the chosen one is always kept
+pub(crate) fn action_dialogue(history: History, messages: Vec<(String, String)>, max_len: u64) -> Dialogue {
+    let mut parts: Vec<_> = messages.into_iter()
+        .map(|(author, text)| Part {
+            role: "user".to_string(),
+            text: format!("{author}: {text}"),
+            message_ids: Vec::new(),
+            template: false,
+        })
+        .collect();
+
+    /* Start from the chosen message, which is last unless the history comes after it */
+    let forwards = matches!(history, History::After(_));
+    if !forwards {
+        parts.reverse();
+    }
+
+    let mut kept = Vec::new();
+    let mut total_len = 0;
+    for part in parts {
+        total_len += part.len();
+        if total_len > max_len && !kept.is_empty() {
+            break;
+        }
+        kept.push(part);
+    }
+
+    if !forwards {
+        kept.reverse();
+    }
+
+    let mut dialogue = Dialogue::new();
+    dialogue.set_max_len(u64::MAX);
+    for part in kept {
+        dialogue.push(&part.role, &part.text);
+    }
+    dialogue
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn messages(texts: &[&str]) -> Vec<(String, String)> {
+        texts.iter().map(|t| ("Bob".to_string(), t.to_string())).collect()
+    }
+
+    fn texts(dialogue: &Dialogue) -> Vec<&str> {
+        dialogue.parts.iter().map(|p| p.text.as_str()).collect()
+    }
+
+    #[test]
+    fn test_action_dialogue() {
+        let dialogue = action_dialogue(History::Before(10), messages(&["one two", "three four", "five"]), 100);
+        assert_eq!(vec!["Bob: one two", "Bob: three four", "Bob: five"], texts(&dialogue));
+
+        /* The furthest messages from the chosen one are dropped */
+        let dialogue = action_dialogue(History::Before(10), messages(&["one two", "three four", "five"]), 6);
+        assert_eq!(vec!["Bob: three four", "Bob: five"], texts(&dialogue));
+        let dialogue = action_dialogue(History::After(10), messages(&["one two", "three four", "five"]), 6);
+        assert_eq!(vec!["Bob: one two", "Bob: three four"], texts(&dialogue));
+
+        /* The chosen message is kept even if it's too long */
+        let dialogue = action_dialogue(History::None, messages(&["one two three"]), 2);
+        assert_eq!(vec!["Bob: one two three"], texts(&dialogue));
+    }
+}
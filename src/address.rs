@@ -0,0 +1,116 @@
+use serenity::all::{Message, RoleId, UserId};
+
+/// How a message addresses the bot
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) enum Address {
+    /// Mentions the bot's user
+    Mention,
+    /// Mentions the role Discord manages for the bot, which has the same name
+    RoleMention,
+    /// Replies to one of the bot's messages
+    Reply,
+    /// Says one of the bot's name triggers, e.g. "hey clutha"
+    Name,
+}
+
+/// What a message can address the bot by
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Addressee<'a> {
+    pub(crate) user_id: UserId,
+    /// The bot's managed roles in the message's guild
+    pub(crate) role_ids: Vec<RoleId>,
+    /// Phrases that address the bot when said as whole words, in any case
+    pub(crate) name_triggers: &'a [String],
+}
+
+impl Addressee<'_> {
+    /// How the message addresses the bot, if it does at all
+    pub(crate) fn addressed_by(&self, msg: &Message) -> Option<Address> {
+        if msg.mentions_user_id(self.user_id) {
+            return Some(Address::Mention);
+        }
+        if msg.mention_roles.iter().any(|r| self.role_ids.contains(r)) {
+            return Some(Address::RoleMention);
+        }
+        if msg.referenced_message.as_ref().is_some_and(|r| r.author.id == self.user_id) {
+            return Some(Address::Reply);
+        }
+        if self.name_triggers.iter().any(|t| contains_phrase(&msg.content, t)) {
+            return Some(Address::Name);
+        }
+        None
+    }
+}
+
+/// Whether the phrase is in the text as whole words, ignoring case
+fn contains_phrase(text: &str, phrase: &str) -> bool {
+    let text = text.to_lowercase();
+    let phrase = phrase.trim().to_lowercase();
+    if phrase.is_empty() {
+        return false;
+    }
+
+    let is_word_char = |c: char| c.is_alphanumeric() || c == '_';
+    text.match_indices(&phrase).any(|(start, _)| {
+        let before = text[..start].chars().next_back();
+        let after = text[start + phrase.len()..].chars().next();
+        !before.is_some_and(is_word_char) && !after.is_some_and(is_word_char)
+    })
+}
+
+/// Parse a comma-separated list of name triggers
+pub(crate) fn parse_name_triggers(value: &str) -> Vec<String> {
+    value.split(',')
+        .map(str::trim)
+        .filter(|t| !t.is_empty())
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use serenity::all::User;
+
+    use super::*;
+
+    fn message(content: &str) -> Message {
+        let mut msg = Message::default();
+        msg.content = content.to_string();
+        msg
+    }
+
+    #[test]
+    fn test_addressed_by() {
+        let triggers = parse_name_triggers("hey clutha, ok bot");
+        let me = Addressee {
+            user_id: UserId::new(1),
+            role_ids: vec![RoleId::new(2)],
+            name_triggers: &triggers,
+        };
+
+        let mut msg = message("hello");
+        assert_eq!(None, me.addressed_by(&msg));
+
+        let mut user = User::default();
+        user.id = UserId::new(1);
+        msg.mentions.push(user.clone());
+        assert_eq!(Some(Address::Mention), me.addressed_by(&msg));
+
+        let mut msg = message("hello");
+        msg.mention_roles.push(RoleId::new(3));
+        assert_eq!(None, me.addressed_by(&msg));
+        msg.mention_roles.push(RoleId::new(2));
+        assert_eq!(Some(Address::RoleMention), me.addressed_by(&msg));
+
+        let mut msg = message("I agree");
+        let mut reply_to = message("What do you think?");
+        reply_to.author = user;
+        msg.referenced_message = Some(Box::new(reply_to));
+        assert_eq!(Some(Address::Reply), me.addressed_by(&msg));
+
+        assert_eq!(Some(Address::Name), me.addressed_by(&message("Hey Clutha, what time is it?")));
+        assert_eq!(Some(Address::Name), me.addressed_by(&message("so... ok bot")));
+        assert_eq!(None, me.addressed_by(&message("hey cluthas")));
+        assert_eq!(None, me.addressed_by(&message("they clutha")));
+    }
+}
@@ -5,7 +5,7 @@ use async_trait::async_trait;
 use reqwest::StatusCode;
 use tracing::error;
 
-use crate::backend::{Backend, Error};
+use crate::backend::{Backend, Error, GenerationOptions};
 use crate::backend::chatgpt::model::{Content, Input, InputMessage, Request, Response};
 
 const BASE_URL: &str = "https://api.openai.com/v1/responses";
@@ -24,7 +24,7 @@ impl ChatGpt {
         }
     }
 
-    fn build_request(&self, prompt: Vec<(String, String)>) -> Request {
+    fn build_request(&self, prompt: Vec<(String, String)>, options: &GenerationOptions) -> Request {
         let mut input = Vec::new();
 
         for (role, text) in prompt.into_iter() {
@@ -40,23 +40,35 @@ impl ChatGpt {
         }
 
         Request {
-            model: self.model.clone(),
+            model: self.model(options).to_string(),
             input,
+            temperature: options.temperature,
+            top_p: options.top_p,
+            max_output_tokens: options.max_output_tokens,
         }
     }
 }
 
 #[async_trait]
 impl Backend for ChatGpt {
+    fn name(&self) -> &'static str {
+        "chatgpt"
+    }
+
+    fn model<'a>(&'a self, options: &'a GenerationOptions) -> &'a str {
+        options.model.as_deref().unwrap_or(&self.model)
+    }
+
     async fn generate_content(
         &self,
         prompt: Vec<(String, String)>,
+        options: &GenerationOptions,
     ) -> Result<String, Error> {
         let client = reqwest::Client::new();
 
         let full_url = format!("{}", BASE_URL);
 
-        let request = self.build_request(prompt);
+        let request = self.build_request(prompt, options);
 
         let Ok(request_str) = serde_json::to_string(&request) else {
             error!("Couldn't serialise request: {:?}", request);
@@ -102,7 +114,7 @@ mod test {
     fn test_build_request() {
         let chatgpt = ChatGpt::new("");
         let prompt = vec![("role1".to_string(), "text1".to_string())];
-        let request = chatgpt.build_request(prompt);
+        let request = chatgpt.build_request(prompt, &GenerationOptions::default());
 
         let json = serde_json::to_string(&request).unwrap();
 
@@ -17,6 +17,12 @@ pub(crate) enum Input {
 pub(crate) struct Request {
     pub(crate) model: String,
     pub(crate) input: Vec<Input>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) temperature: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) top_p: Option<f32>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) max_output_tokens: Option<u32>,
 }
 
 #[derive(Debug, Serialize, Deserialize)]
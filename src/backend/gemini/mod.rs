@@ -5,7 +5,7 @@ use async_trait::async_trait;
 use reqwest::StatusCode;
 use tracing::error;
 
-use crate::backend::{get_client, map_client_error, Backend, Error};
+use crate::backend::{get_client, map_client_error, Backend, Error, GenerationOptions};
 use crate::backend::gemini::model::*;
 
 const BASE_URL: &str = "https://generativelanguage.googleapis.com/v1";
@@ -28,15 +28,25 @@ impl Gemini {
 
 #[async_trait]
 impl Backend for Gemini {
+    fn name(&self) -> &'static str {
+        "gemini"
+    }
+
+    fn model<'a>(&'a self, options: &'a GenerationOptions) -> &'a str {
+        options.model.as_deref().unwrap_or(&self.model)
+    }
+
     async fn generate_content(
         &self,
         prompt: Vec<(String, String)>,
+        options: &GenerationOptions,
     ) -> Result<String, Error> {
         let client = get_client();
 
-        let full_url = format!("{}/{}:{}", BASE_URL, self.model, GENERATE_METHOD);
+        let model = self.model(options);
+        let full_url = format!("{}/{}:{}", BASE_URL, model, GENERATE_METHOD);
 
-        let request = build_request(prompt);
+        let request = build_request(prompt, options);
 
         let Ok(request_str) = serde_json::to_string(&request) else {
             error!("Couldn't serialise request: {:?}", request);
@@ -74,11 +84,17 @@ impl Backend for Gemini {
     }
 }
 
-fn build_request(prompt: Vec<(String, String)>) -> GenerateContentRequest {
+fn build_request(prompt: Vec<(String, String)>, options: &GenerationOptions) -> GenerateContentRequest {
     let mut contents = Vec::new();
+    let mut system_parts = Vec::new();
 
     for (role, text) in prompt.into_iter() {
         let part = Part { text };
+        /* Gemini takes system text separately from the conversation */
+        if role == "system" {
+            system_parts.push(part);
+            continue;
+        }
         let content = Content {
             parts: vec![part],
             role,
@@ -86,6 +102,11 @@ fn build_request(prompt: Vec<(String, String)>) -> GenerateContentRequest {
         contents.push(content);
     }
 
+    let system_instruction = (!system_parts.is_empty()).then(|| Content {
+        parts: system_parts,
+        role: "system".to_string(),
+    });
+
     let safety_settings = vec![
         // HarmCategory.HARM_CATEGORY_HATE_SPEECH,
         // HarmCategory.HARM_CATEGORY_SEXUALLY_EXPLICIT,
@@ -94,7 +115,17 @@ fn build_request(prompt: Vec<(String, String)>) -> GenerateContentRequest {
         // HarmCategory.HARM_CATEGORY_CIVIC_INTEGRITY,
     ];
 
-    GenerateContentRequest { contents, safety_settings }
+    let generation_config = if options.temperature.is_some() || options.top_p.is_some() || options.max_output_tokens.is_some() {
+        Some(GenerationConfig {
+            temperature: options.temperature,
+            top_p: options.top_p,
+            max_output_tokens: options.max_output_tokens,
+        })
+    } else {
+        None
+    };
+
+    GenerateContentRequest { system_instruction, contents, safety_settings, generation_config }
 }
 
 #[cfg(test)]
@@ -104,7 +135,7 @@ mod test {
     #[test]
     fn test_build_request() {
         let prompt = vec![("role1".to_string(), "text1".to_string())];
-        let request = build_request(prompt);
+        let request = build_request(prompt, &GenerationOptions::default());
 
         let json = serde_json::to_string(&request).unwrap();
 
@@ -113,4 +144,34 @@ mod test {
             json
         );
     }
+
+    #[test]
+    fn test_build_request_with_options() {
+        let prompt = vec![("role1".to_string(), "text1".to_string())];
+        let options = GenerationOptions { temperature: Some(0.5), max_output_tokens: Some(100), ..Default::default() };
+        let request = build_request(prompt, &options);
+
+        let json = serde_json::to_string(&request).unwrap();
+
+        assert_eq!(
+            "{\"contents\":[{\"parts\":[{\"text\":\"text1\"}],\"role\":\"role1\"}],\"generationConfig\":{\"temperature\":0.5,\"maxOutputTokens\":100}}",
+            json
+        );
+    }
+
+    #[test]
+    fn test_build_request_with_system() {
+        let prompt = vec![
+            ("system".to_string(), "text1".to_string()),
+            ("user".to_string(), "text2".to_string()),
+        ];
+        let request = build_request(prompt, &GenerationOptions::default());
+
+        let json = serde_json::to_string(&request).unwrap();
+
+        assert_eq!(
+            "{\"systemInstruction\":{\"parts\":[{\"text\":\"text1\"}],\"role\":\"system\"},\"contents\":[{\"parts\":[{\"text\":\"text2\"}],\"role\":\"user\"}]}",
+            json
+        );
+    }
 }
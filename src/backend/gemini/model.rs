@@ -88,9 +88,23 @@ pub(crate) struct SafetySetting {
 
 #[derive(Debug, Serialize, Deserialize)]
 pub(crate) struct GenerateContentRequest {
+    #[serde(rename = "systemInstruction", skip_serializing_if = "Option::is_none")]
+    pub(crate) system_instruction: Option<Content>,
     pub(crate) contents: Vec<Content>,
     #[serde(skip_serializing_if = "Vec::is_empty")]
     pub(crate) safety_settings: Vec<SafetySetting>,
+    #[serde(rename = "generationConfig", skip_serializing_if = "Option::is_none")]
+    pub(crate) generation_config: Option<GenerationConfig>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+pub(crate) struct GenerationConfig {
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) temperature: Option<f32>,
+    #[serde(rename = "topP", skip_serializing_if = "Option::is_none")]
+    pub(crate) top_p: Option<f32>,
+    #[serde(rename = "maxOutputTokens", skip_serializing_if = "Option::is_none")]
+    pub(crate) max_output_tokens: Option<u32>,
 }
 
 #[cfg(test)]
@@ -41,11 +41,27 @@ impl From<serde_json::Error> for Error {
     }
 }
 
+/// Per-request settings, overriding the backend's defaults
+#[derive(Clone, Debug, Default, PartialEq)]
+pub(crate) struct GenerationOptions {
+    pub(crate) model: Option<String>,
+    pub(crate) temperature: Option<f32>,
+    pub(crate) top_p: Option<f32>,
+    pub(crate) max_output_tokens: Option<u32>,
+}
+
 #[async_trait]
 pub(crate) trait Backend: Send + Sync {
+    /// Short name of the backend, as used in prompt files
+    fn name(&self) -> &'static str;
+
+    /// The model that a request with these options is sent to
+    fn model<'a>(&'a self, options: &'a GenerationOptions) -> &'a str;
+
     async fn generate_content(
         &self,
         prompt: Vec<(String, String)>,
+        options: &GenerationOptions,
     ) -> Result<String, Error>;
 }
 
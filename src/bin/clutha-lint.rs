@@ -0,0 +1,6 @@
+use std::process::ExitCode;
+
+fn main() -> ExitCode {
+    let paths: Vec<String> = std::env::args().skip(1).collect();
+    clutha::lint::run(&paths)
+}
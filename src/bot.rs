@@ -1,41 +1,81 @@
 use std::collections::HashMap;
-use std::path::PathBuf;
+use std::fmt::Display;
+use std::path::{Path, PathBuf};
 use std::sync::Arc;
+use std::time::Duration;
 
 use serenity::all::standard::CommandResult;
-use serenity::all::{CacheHttp, Channel, ChannelId, ChannelType, Context, Message, MessageId};
-use serenity::builder::CreateThread;
+use serenity::all::{Cache, CacheHttp, Channel, ChannelId, ChannelType, Context, GuildId, Message, MessageId, MessageUpdateEvent, Reaction, RoleId, Timestamp, User, UserId};
+use serenity::builder::{CreateAttachment, CreateMessage, CreateThread, GetMessages};
 use tokio::sync::Mutex;
-use tracing::info;
+use tracing::{error, info, warn};
 
-use crate::backend::Backend;
+use crate::action::{action_dialogue, Action, History};
+use crate::address::{Address, Addressee};
+use crate::backend::{Backend, GenerationOptions};
 use crate::channel::{Mode, State};
-use crate::dialogue::{Dialogue, Part};
-use crate::prompt::{load_prompt, Prompt};
+use crate::dialogue::{quote_reply, Dialogue, MAXIMUM_DIALOGUE_LEN};
+use crate::feedback::{dialogue_hash, write_feedback, Exchange, Feedback, Vote, FEEDBACK_FILE};
+use crate::markup;
+use crate::markup::Token;
+use crate::memory::{load_memory, Memory, SharedMemory, CHANNEL_MEMORY_DIR, GUILD_MEMORY_DIR};
+use crate::overflow::{split_response, Segment};
+use crate::prompt::{write_prompt, Catalogue, Prompt, DEFAULT_PROMPT, GUILD_PROMPT_DIR};
+use crate::store;
+use crate::store::PromptStore;
+use crate::template::Variables;
 
 pub(crate) struct Bot {
     pub(crate) backend: Box<dyn Backend>,
     pub(crate) channels: Arc<Mutex<HashMap<ChannelId, Arc<Mutex<State>>>>>,
+    pub(crate) guild_memories: Arc<Mutex<HashMap<GuildId, SharedMemory>>>,
+    /// Number of past messages to read into the dialogue when a channel's state is created
+    pub(crate) backfill_len: u8,
+    pub(crate) prompt_store: PromptStore,
+    /// Store of the context menu actions' prompts, if there is one
+    pub(crate) action_store: Option<PromptStore>,
+    pub(crate) catalogue: Catalogue,
+    /// How often to check the prompt directory for changes, if at all
+    pub(crate) prompt_watch_interval: Option<Duration>,
+    /// Phrases that address the bot like a mention does, e.g. "hey clutha"
+    pub(crate) name_triggers: Vec<String>,
+    /// Users' reactions to responses, saved in the feedback file
+    pub(crate) feedback: Feedback,
 }
 
+/// Responses longer than this are put in a new thread, unless the prompt says otherwise
+pub(crate) const DEFAULT_THREAD_THRESHOLD: usize = 200;
+
+/// How often the feedback is saved, if it has changed
+pub(crate) const FEEDBACK_SAVE_INTERVAL: Duration = Duration::from_secs(30);
+
+/// Discord won't return more than this many messages per request
+pub(crate) const MAX_BACKFILL_LEN: u8 = 100;
+
 impl Bot {
     pub(crate) async fn handle_dialogue(&mut self, ctx: &Context, msg: &Message) -> CommandResult {
         let state = self.channel_state(ctx, msg.channel_id).await?;
         let mut state = state.lock().await;
 
-        // TODO - some mentions are mentioning the role of the same name, and it would be
-        //  nice to pick those up, too
-        let mentions_me = msg.mentions_me(ctx).await.unwrap_or(false);
+        let addressed = self.addressed_by(ctx, msg).is_some();
 
-        if !self.should_process(ctx, msg, &state, mentions_me) {
+        if !self.should_process(ctx, msg, &state, addressed) {
             return Ok(());
         }
 
-        let text = &msg.content;
-        state.process_user_text(text);
-        println!("### {}", text);
+        state.learn_user(&msg.author);
+        for user in &msg.mentions {
+            state.learn_user(user);
+        }
+
+        /* The message may already have been read when the state was created */
+        if !state.dialogue.contains_message(msg.id) {
+            let text = user_text(&ctx.cache, &state, msg.guild_id, &msg.content, &msg.mentions, msg.referenced_message.as_deref());
+            state.process_user_text(&text, msg.id);
+            println!("### {}", text);
+        }
 
-        if !self.should_respond(ctx, msg, &state, mentions_me) {
+        if !self.should_respond(ctx, msg, &state, addressed) {
             return Ok(())
         }
 
@@ -51,17 +91,26 @@ impl Bot {
 
         let typing = channel_id.start_typing(&ctx.http);
 
-        let prompt = state.assemble_prompt();
-        let result = match self.backend.generate_content(prompt).await {
+        let variables = template_variables(ctx, channel_id, original_msg.map(|m| &m.author)).await;
+        let prompt = state.assemble_prompt(&variables);
+        let mut exchange = Exchange {
+            guild_id: state.guild_id,
+            prompt: state.prompt.filename.clone(),
+            dialogue_hash: dialogue_hash(&prompt),
+            backend: self.backend.name().to_string(),
+            model: self.backend.model(&state.prompt.metadata.generation).to_string(),
+            ..Exchange::default()
+        };
+        let result = match self.backend.generate_content(prompt, &state.prompt.metadata.generation).await {
             Ok(result) => result,
             Err(err) => {
-                channel_id.say(&ctx, format!("Error: {err:?}")).await?;
+                send_message(ctx, channel_id, CreateMessage::new().content(format!("Error: {err:?}"))).await?;
                 return Err(err.into());
             }
         };
 
-        let result_segments = prepare_response(&result);
-        let mut dest_channel = channel_id;
+        let response = markup::encode(&result, &state.known_users);
+        let result_segments = prepare_response(&response);
 
         /* If the response length is large, put the response in a thread */
         let is_thread = matches!(
@@ -69,7 +118,8 @@ impl Bot {
             Some(ChannelType::PublicThread | ChannelType::PrivateThread)
         );
         let total_len = result_segments.iter().map(|s| s.len()).sum::<usize>();
-        let create_thread = !is_thread && total_len > 200 && original_msg.is_some();
+        let thread_threshold = state.prompt.metadata.thread_threshold.unwrap_or(DEFAULT_THREAD_THRESHOLD);
+        let create_thread = !is_thread && total_len > thread_threshold && original_msg.is_some();
 
         if create_thread {
             let thread_name = self.suggest_thread_name(&state.dialogue).await?;
@@ -78,49 +128,194 @@ impl Bot {
             let r = channel_id.create_thread_from_message(ctx, original_msg.unwrap().id, CreateThread::new(thread_name)).await?;
             let thread_id = r.id;
 
-            /* Create a new state for the thread, based on the channel state */
-            let state2 = self.channel_state(ctx, thread_id).await?;
+            let state2 = self.fork_state(&state, thread_id).await;
             let mut state2 = state2.lock().await;
 
-            /* Copy the current state, but set the mode to active */
-            state2.clone_from(&state);
-            state2.mode = Mode::Active;
-
-            state2.process_model_text(&result);
-            dest_channel = r.id;
+            exchange.message_ids = send_segments(ctx, r.id, result_segments).await?;
+            state2.process_model_text(&result, &exchange.message_ids);
         } else {
-            state.process_model_text(&result);
+            exchange.message_ids = send_segments(ctx, channel_id, result_segments).await?;
+            state.process_model_text(&result, &exchange.message_ids);
         }
 
-        println!(">>> {}\n", result);
+        self.feedback.record(exchange);
 
-        for segment in result_segments {
-            dest_channel.say(&ctx, segment).await?;
-        }
+        println!(">>> {}\n", result);
 
         typing.stop();
 
         Ok(())
     }
 
-    fn should_process(&self, ctx: &Context, msg: &Message, state: &State, mentions_me: bool) -> bool {
+    /// Run an action on a message for a user, and return the answer; the channel's dialogue is
+    /// left as it is
+    pub(crate) async fn run_action(&self, ctx: &Context, action: Action, prompt: &Prompt, msg: &Message, guild_id: Option<GuildId>, user: &User) -> CommandResult<String> {
+        let channel_id = msg.channel_id;
+        if msg.content.trim().is_empty() {
+            return Err("That message has no text".into());
+        }
+
+        let mut messages = match action.history() {
+            History::None => Vec::new(),
+            History::Before(limit) => channel_id.messages(ctx, GetMessages::new().before(msg.id).limit(limit)).await?,
+            History::After(limit) => channel_id.messages(ctx, GetMessages::new().after(msg.id).limit(limit)).await?,
+        };
+        messages.retain(|m| {
+            let text = m.content.trim();
+            !text.is_empty() && !text.starts_with('~')
+        });
+        messages.push(msg.clone());
+        messages.sort_by_key(|m| m.id);
+
+        let messages = messages.iter()
+            .map(|m| {
+                let text = readable_content(Some(&ctx.cache), guild_id, &m.content, &m.mentions);
+                (m.author.display_name().to_string(), text)
+            })
+            .collect();
+        let max_len = MAXIMUM_DIALOGUE_LEN.saturating_sub(prompt.prompt.total_len);
+        let request_state = State {
+            prompt: prompt.clone(),
+            dialogue: action_dialogue(action.history(), messages, max_len),
+            ..State::new(Mode::Off, None)
+        };
+
+        let variables = template_variables(ctx, channel_id, Some(user)).await;
+        let result = self.backend.generate_content(request_state.assemble_prompt(&variables), &prompt.metadata.generation).await?;
+        info!("Ran action {action:?} on message {}", msg.id);
+
+        Ok(result)
+    }
+
+    /// Load the prompt for an action
+    pub(crate) fn load_action_prompt(&self, action: Action) -> Result<Prompt, store::Error> {
+        let name = action.prompt_name();
+        let store = self.action_store.as_ref().ok_or_else(|| store::Error::NotFound(name.to_string()))?;
+        store.load(name)
+    }
+
+    /// Branch the channel's dialogue into a new thread, optionally with a different prompt
+    pub(crate) async fn fork(&mut self, ctx: &Context, channel_id: ChannelId, guild_id: Option<GuildId>, name: Option<String>, prompt_name: Option<&str>) -> CommandResult<ChannelId> {
+        let state = self.channel_state(ctx, channel_id).await?;
+        let state = state.lock().await.clone();
+
+        let thread_name = match name {
+            Some(name) => name,
+            None => self.suggest_thread_name(&state.dialogue).await?,
+        };
+        info!("Forking thread: {thread_name}");
+
+        let builder = CreateThread::new(thread_name).kind(ChannelType::PublicThread);
+        let thread_id = channel_id.create_thread(ctx, builder).await?.id;
+        self.fork_state(&state, thread_id).await;
+
+        if let Some(prompt_name) = prompt_name {
+            if self.set_prompt(ctx, thread_id, guild_id, prompt_name).await?.needs_response() {
+                self.do_ai_response(ctx, thread_id, None).await?;
+            }
+        }
+
+        Ok(thread_id)
+    }
+
+    /// Create the state for a new thread, copied from its parent channel's state
+    async fn fork_state(&self, state: &State, thread_id: ChannelId) -> Arc<Mutex<State>> {
+        /* Copy the current state, but set the mode to active */
+        let mut state2 = state.clone();
+        state2.mode = Mode::Active;
+
+        let state2 = Arc::new(Mutex::new(state2));
+        self.channels.lock().await.insert(thread_id, state2.clone());
+        state2
+    }
+
+    /// Update the dialogue part for a message that was edited
+    pub(crate) async fn handle_edit(&self, ctx: &Context, event: &MessageUpdateEvent, text: &str) {
+        let (channel_id, message_id) = (event.channel_id, event.id);
+        let Some(state) = self.existing_channel_state(channel_id).await else { return };
+        let mentions = event.mentions.as_deref().unwrap_or_default();
+
+        /* The event may leave out the message being replied to, if it hasn't changed */
+        let referenced = match (&event.referenced_message, &event.message_reference) {
+            (Some(referenced), _) => referenced.clone(),
+            (None, Some(Some(reference))) => match reference.message_id {
+                Some(id) => channel_id.message(ctx, id).await.ok().map(Box::new),
+                None => None,
+            },
+            (None, _) => None,
+        };
+
+        let mut state = state.lock().await;
+        let text = user_text(&ctx.cache, &state, event.guild_id, text, mentions, referenced.as_deref());
+        if state.process_edit(message_id, &text) {
+            info!("Updated edited message {message_id} in dialogue");
+        }
+    }
+
+    /// Remove the dialogue part for a message that was deleted
+    pub(crate) async fn handle_delete(&self, channel_id: ChannelId, message_id: MessageId) {
+        let Some(state) = self.existing_channel_state(channel_id).await else { return };
+        if state.lock().await.process_delete(message_id) {
+            info!("Removed deleted message {message_id} from dialogue");
+        }
+    }
+
+    /// Count a 👍 or 👎 reaction to one of the bot's responses, or take it back if it was removed
+    pub(crate) fn handle_reaction(&mut self, ctx: &Context, reaction: &Reaction, added: bool) {
+        let Some(vote) = Vote::from_reaction(&reaction.emoji) else { return };
+        let Some(user_id) = reaction.user_id else { return };
+        if user_id == ctx.cache.current_user().id {
+            return;
+        }
+
+        let message_id = reaction.message_id;
+        let changed = if added {
+            self.feedback.vote(message_id, user_id, vote)
+        } else {
+            self.feedback.unvote(message_id, user_id, vote)
+        };
+        if changed {
+            info!("Feedback on message {message_id}: {vote:?} {}", if added { "added" } else { "removed" });
+        }
+    }
+
+    /// How a message addresses the bot, if it does; this is what decides whether the bot has been
+    /// spoken to
+    fn addressed_by(&self, ctx: &Context, msg: &Message) -> Option<Address> {
+        let user_id = ctx.cache.current_user().id;
+        let role_ids = msg.guild_id
+            .and_then(|g| ctx.cache.guild(g).map(|guild| guild.roles.values()
+                .filter(|r| r.tags.bot_id == Some(user_id))
+                .map(|r| r.id)
+                .collect()))
+            .unwrap_or_default();
+
+        let addressee = Addressee {
+            user_id,
+            role_ids,
+            name_triggers: &self.name_triggers,
+        };
+        addressee.addressed_by(msg)
+    }
+
+    fn should_process(&self, ctx: &Context, msg: &Message, state: &State, addressed: bool) -> bool {
         if msg.is_own(ctx) {
             return false;
         }
 
         match state.mode {
             Mode::Off => false,
-            Mode::Passive => mentions_me,
+            Mode::Passive => addressed,
             Mode::Lurking => true,
             Mode::Active => true,
         }
     }
 
-    fn should_respond(&self, _ctx: &Context, _msg: &Message, state: &State, mentions_me: bool) -> bool {
+    fn should_respond(&self, _ctx: &Context, _msg: &Message, state: &State, addressed: bool) -> bool {
         match state.mode {
             Mode::Off => false,
-            Mode::Passive => mentions_me,
-            Mode::Lurking => mentions_me,
+            Mode::Passive => addressed,
+            Mode::Lurking => addressed,
             Mode::Active => true,
         }
     }
@@ -132,11 +327,11 @@ impl Bot {
         Print a single suggestion with no extra text, less than 100 characters.\
         This should be noun-phrase, not a full sentence.");
         let request_state = State {
-            mode: Mode::Off,
-            prompt: Prompt { prompt: request_prompt, initial: Dialogue::new(), filename: String::new() },
+            prompt: Prompt { prompt: request_prompt, initial: Dialogue::new(), ..Prompt::default() },
             dialogue: dialogue.clone(),
+            ..State::new(Mode::Off, None)
         };
-        let result = self.backend.generate_content(request_state.assemble_prompt()).await?;
+        let result = self.backend.generate_content(request_state.assemble_prompt(&Variables::default()), &GenerationOptions::default()).await?;
 
         let mut thread_name = result.replace('\n', " ");
         //TODO truncate could panic if there is a multibyte character
@@ -149,65 +344,411 @@ impl Bot {
         &mut self,
         ctx: &Context,
         channel_id: ChannelId,
+        guild_id: Option<GuildId>,
         prompt_name: &str,
-    ) -> CommandResult<bool> {
-        let mut path = PathBuf::new();
-        path.push("prompts/");
-        path.push(format!("{}.txt", prompt_name));
-        let prompt = load_prompt(&path)?;
+    ) -> CommandResult<Prompt> {
+        let prompt = self.load_prompt(guild_id, prompt_name)?;
+
+        if let Some(backend) = &prompt.metadata.backend {
+            if backend != self.backend.name() {
+                warn!("Prompt {prompt_name} is for backend {backend}, but using {}", self.backend.name());
+            }
+        }
 
         let state = self.channel_state(ctx, channel_id).await?;
         let mut state = state.lock().await;
 
         state.set_prompt(&prompt);
+        if let Some(mode) = prompt.metadata.mode {
+            state.mode = mode;
+        }
 
-        /* Check if the last item in the prompt was from the user */
-        let needs_response = match prompt.initial.parts.iter().last() {
-            Some(Part { role, text }) => role == "user",
-            None => false,
-        };
+        let unknown = prompt.unknown_variables();
+        if !unknown.is_empty() {
+            warn!("Prompt {prompt_name} has unknown variables: {}", unknown.join(", "));
+        }
 
-        Ok(needs_response)
+        Ok(prompt)
     }
 
-    pub(crate) async fn channel_state(&self, cache: impl CacheHttp, channel_id: ChannelId) -> serenity::Result<Arc<Mutex<State>>> {
-        let mut channels = self.channels.lock().await;
-        if let Some(channel) = channels.get(&channel_id) { return Ok(channel.clone()) };
+    /// Load a prompt by name; a guild's own prompts shadow the global ones
+    pub(crate) fn load_prompt(&self, guild_id: Option<GuildId>, prompt_name: &str) -> Result<Prompt, store::Error> {
+        if let Some(store) = guild_id.and_then(|g| self.guild_prompt_store(g).ok()) {
+            match store.load(prompt_name) {
+                Err(store::Error::NotFound(_)) => (),
+                result => return result,
+            }
+        }
+        self.prompt_store.load(prompt_name)
+    }
+
+    /// Re-read the prompt catalogue, and update every channel whose prompt has changed, keeping
+    /// its dialogue; returns the number of channels updated
+    pub(crate) async fn reload_prompts(&mut self) -> Result<usize, store::Error> {
+        self.catalogue = Catalogue::load(&self.prompt_store)?;
+
+        let channels: Vec<_> = self.channels.lock().await.values().cloned().collect();
+        let mut num_updated = 0;
+        for state in channels {
+            let mut state = state.lock().await;
+
+            /* Imported prompts have no name, and can't be reloaded */
+            let Some(name) = state.prompt.name.clone() else { continue };
+            let prompt = match self.load_prompt(state.guild_id, &name) {
+                Ok(prompt) => prompt,
+                Err(err) => {
+                    warn!("Couldn't reload prompt {name}: {err}");
+                    continue;
+                }
+            };
+
+            if prompt.filename != state.prompt.filename || write_prompt(&prompt) != write_prompt(&state.prompt) {
+                state.update_prompt(&prompt);
+                num_updated += 1;
+            }
+        }
+
+        info!("Reloaded {} prompts, updating {num_updated} channels", self.catalogue.prompts.len());
+
+        Ok(num_updated)
+    }
 
+    /// The store of prompts uploaded to a guild, which is an error if none have been; the
+    /// backend settings in uploaded prompts are ignored
+    pub(crate) fn guild_prompt_store(&self, guild_id: GuildId) -> Result<PromptStore, store::Error> {
+        Ok(PromptStore::open(guild_prompt_dir(guild_id))?.with_fragments(self.prompt_store.fragments().cloned()).without_backend_settings())
+    }
+
+    /// The store of prompts uploaded to a guild, created if necessary
+    pub(crate) fn create_guild_prompt_store(&self, guild_id: GuildId) -> Result<PromptStore, store::Error> {
+        Ok(PromptStore::create(guild_prompt_dir(guild_id))?.with_fragments(self.prompt_store.fragments().cloned()).without_backend_settings())
+    }
+
+    pub(crate) async fn channel_state(&self, cache: impl CacheHttp, channel_id: ChannelId) -> CommandResult<Arc<Mutex<State>>> {
+        if let Some(channel) = self.existing_channel_state(channel_id).await { return Ok(channel) };
+
+        /* Don't hold the lock while creating the state, as it may look at other channels */
         let channel = self.new_channel_state(cache, channel_id).await?;
-        let channel = Arc::new(Mutex::new(channel));
-        channels.insert(channel_id, channel.clone());
+        let mut channels = self.channels.lock().await;
+        let channel = channels.entry(channel_id)
+            .or_insert_with(|| Arc::new(Mutex::new(channel)))
+            .clone();
         Ok(channel)
     }
 
-    pub(crate) async fn new_channel_state(&self, cache: impl CacheHttp, channel_id: ChannelId) -> serenity::Result<State> {
-        let channel = channel_id.to_channel(cache).await?;
-        let mode = match &channel {
+    /// The guild's notes, read from disk the first time they're needed
+    pub(crate) async fn guild_memory(&self, guild_id: GuildId) -> SharedMemory {
+        self.guild_memories.lock().await.entry(guild_id)
+            .or_insert_with(|| Arc::new(std::sync::Mutex::new(saved_memory(GUILD_MEMORY_DIR, guild_id).unwrap_or_default())))
+            .clone()
+    }
+
+    /// The state of a thread's parent channel, created if the parent hasn't been used yet
+    async fn parent_channel_state(&self, cache: impl CacheHttp, channel: &Channel) -> Option<Arc<Mutex<State>>> {
+        let Channel::Guild(gc) = channel else { return None };
+        gc.thread_metadata?;
+        let parent_id = gc.parent_id?;
+        if let Some(parent) = self.existing_channel_state(parent_id).await {
+            return Some(parent);
+        }
+
+        /* A parent is never a thread, so its state doesn't come from a parent of its own */
+        let parent = match self.new_parent_state(&cache, parent_id).await {
+            Ok(parent) => parent,
+            Err(err) => {
+                warn!("Couldn't create the state of channel {parent_id}, the parent of {}: {err}", gc.id);
+                return None;
+            }
+        };
+        let mut channels = self.channels.lock().await;
+        let parent = channels.entry(parent_id)
+            .or_insert_with(|| Arc::new(Mutex::new(parent)))
+            .clone();
+        Some(parent)
+    }
+
+    /// A new state for a thread's parent channel
+    async fn new_parent_state(&self, cache: impl CacheHttp, channel_id: ChannelId) -> CommandResult<State> {
+        let channel = channel_id.to_channel(&cache).await?;
+        let mut state = self.default_channel_state(&channel).await?;
+
+        /* Forum channels have no messages of their own to read */
+        let is_forum = matches!(&channel, Channel::Guild(gc) if gc.kind == ChannelType::Forum);
+        if self.backfill_len > 0 && !is_forum {
+            self.backfill(&cache, channel_id, &mut state, self.backfill_len).await?;
+        }
+
+        Ok(state)
+    }
+
+    async fn existing_channel_state(&self, channel_id: ChannelId) -> Option<Arc<Mutex<State>>> {
+        self.channels.lock().await.get(&channel_id).cloned()
+    }
+
+    pub(crate) async fn new_channel_state(&self, cache: impl CacheHttp, channel_id: ChannelId) -> CommandResult<State> {
+        let channel = channel_id.to_channel(&cache).await?;
+        let mut state = match self.parent_channel_state(&cache, &channel).await {
+            /* Threads inherit their parent's state, apart from the dialogue */
+            Some(parent) => {
+                let parent = parent.lock().await;
+                let mut state = State::new(parent.mode, parent.guild_memory.clone());
+                state.guild_id = parent.guild_id;
+                state.memory = saved_memory(CHANNEL_MEMORY_DIR, channel_id).unwrap_or_else(|| parent.memory.clone());
+                state.set_prompt(&parent.prompt);
+                state
+            }
+            None => self.default_channel_state(&channel).await?,
+        };
+
+        if let Some(starter) = thread_starter_message(&cache, &channel).await {
+            let guild_id = starter.guild_id.or(state.guild_id);
+            let text = readable_content(cache.cache(), guild_id, &starter.content, &starter.mentions);
+            state.process_user_text(&text, starter.id);
+        }
+
+        if self.backfill_len > 0 {
+            self.backfill(&cache, channel_id, &mut state, self.backfill_len).await?;
+        }
+
+        Ok(state)
+    }
+
+    /// The state of a channel that hasn't been used yet, with the default prompt
+    async fn default_channel_state(&self, channel: &Channel) -> CommandResult<State> {
+        let mode = match channel {
             Channel::Guild(gc) if gc.thread_metadata.is_none() => Mode::Active,
             Channel::Guild(_) => Mode::Lurking,
             Channel::Private(_) => Mode::Active,
             _ => Mode::Passive,
         };
-        let prompt = load_prompt("prompts/default.txt")?;
-        let mut state = State {
-            mode,
-            prompt: Prompt::default(),
-            dialogue: Dialogue::new(),
+        let guild_id = match channel {
+            Channel::Guild(gc) => Some(gc.guild_id),
+            _ => None,
+        };
+        let guild_memory = match guild_id {
+            Some(guild_id) => Some(self.guild_memory(guild_id).await),
+            None => None,
         };
+        let prompt = self.load_prompt(guild_id, DEFAULT_PROMPT)?;
+        let mut state = State::new(mode, guild_memory);
+        state.guild_id = guild_id;
+        state.memory = saved_memory(CHANNEL_MEMORY_DIR, channel.id()).unwrap_or_default();
         state.set_prompt(&prompt);
         Ok(state)
     }
+
+    /// Read the channel's recent message history into its dialogue, after whatever isn't from
+    /// those messages, such as the prompt's initial dialogue; returns the number of messages read
+    pub(crate) async fn backfill(&self, cache: impl CacheHttp, channel_id: ChannelId, state: &mut State, limit: u8) -> serenity::Result<usize> {
+        let limit = limit.min(MAX_BACKFILL_LEN);
+        let messages = channel_id.messages(&cache, GetMessages::new().limit(limit)).await?;
+        let my_id = match cache.cache() {
+            Some(c) => c.current_user().id,
+            None => cache.http().get_current_user().await?.id,
+        };
+
+        /* Messages are returned newest first */
+        let history: Vec<_> = messages.iter().rev()
+            .filter(|m| {
+                let text = m.content.trim();
+                !text.is_empty() && !text.starts_with('~')
+            })
+            .collect();
+
+        if history.is_empty() {
+            return Ok(0);
+        }
+
+        /* Messages already in the dialogue are read again, in order */
+        let message_ids: Vec<_> = history.iter().map(|m| m.id).collect();
+        state.dialogue.remove_messages(&message_ids);
+        for msg in &history {
+            /* The bot's own mentions were encoded from the model's `@name`s, so decode to those */
+            let content = readable_content(cache.cache(), msg.guild_id, &msg.content, &msg.mentions);
+            if msg.author.id == my_id {
+                state.process_model_text(&content, &[msg.id]);
+            } else {
+                state.learn_user(&msg.author);
+                state.process_user_text(&content, msg.id);
+            }
+        }
+
+        let count = history.len();
+        info!("Backfilled {count} messages in channel {channel_id}");
+
+        Ok(count)
+    }
+}
+
+/// Values for prompt template variables, from the Discord context
+async fn template_variables(ctx: &Context, channel_id: ChannelId, user: Option<&User>) -> Variables {
+    let bot_name = ctx.cache.current_user().display_name().to_string();
+    let user = user.map_or("someone".to_string(), |u| u.display_name().to_string());
+
+    let (channel, guild) = match channel_id.to_channel(ctx).await {
+        Ok(Channel::Guild(gc)) => {
+            let guild = ctx.cache.guild(gc.guild_id).map(|g| g.name.clone()).unwrap_or_default();
+            (gc.name, guild)
+        }
+        _ => ("a direct message".to_string(), "no server".to_string()),
+    };
+
+    Variables {
+        bot_name,
+        user,
+        channel,
+        guild,
+        now: Timestamp::now().to_string(),
+    }
+}
+
+/// Reload the prompts whenever the prompt directory changes
+pub(crate) async fn watch_prompts(bot: Arc<Mutex<Bot>>, interval: Duration) {
+    let store = bot.lock().await.prompt_store.clone();
+    let mut last_modified = store.modified().ok();
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+
+        let modified = store.modified().ok();
+        if modified == last_modified {
+            continue;
+        }
+        last_modified = modified;
+
+        info!("Prompt directory changed; reloading");
+        if let Err(err) = bot.lock().await.reload_prompts().await {
+            error!("Couldn't reload prompts: {err}");
+        }
+    }
+}
+
+/// Save the feedback every so often, if it has changed
+pub(crate) async fn save_feedback_periodically(bot: Arc<Mutex<Bot>>, interval: Duration) {
+    let mut ticker = tokio::time::interval(interval);
+
+    loop {
+        ticker.tick().await;
+        flush_feedback(&bot).await;
+    }
+}
+
+/// Save the feedback if it has changed; only serialising it is done with the bot locked, and the
+/// file is written on a blocking thread
+pub(crate) async fn flush_feedback(bot: &Mutex<Bot>) {
+    let text = match bot.lock().await.feedback.take_unsaved() {
+        None => return,
+        Some(Ok(text)) => text,
+        Some(Err(err)) => {
+            error!("Couldn't serialise feedback: {err}");
+            return;
+        }
+    };
+
+    let result = tokio::task::spawn_blocking(move || write_feedback(FEEDBACK_FILE, &text)).await;
+    let err = match result {
+        Ok(Ok(())) => return,
+        Ok(Err(err)) => err.to_string(),
+        Err(err) => err.to_string(),
+    };
+    error!("Couldn't save feedback to {FEEDBACK_FILE}: {err}");
+    bot.lock().await.feedback.mark_changed();
+}
+
+/// A user's message as it goes in the dialogue; a reply quotes the message it's replying to, if
+/// the model hasn't already seen it
+fn user_text(cache: &Arc<Cache>, state: &State, guild_id: Option<GuildId>, text: &str, mentions: &[User], referenced: Option<&Message>) -> String {
+    let content = readable_content(Some(cache), guild_id, text, mentions);
+    match referenced {
+        Some(r) if !state.dialogue.contains_message(r.id) => {
+            let quoted = readable_content(Some(cache), guild_id, &r.content, &r.mentions);
+            quote_reply(r.author.display_name(), &quoted, &content)
+        }
+        _ => content,
+    }
+}
+
+/// Notes saved under a guild's or channel's ID, if any; notes that can't be read are logged and
+/// left out
+fn saved_memory(dir: &str, id: impl Display) -> Option<Memory> {
+    match load_memory(dir, &id) {
+        Ok(memory) => memory,
+        Err(err) => {
+            warn!("Couldn't read the notes in {dir}/{id}.json: {err}");
+            None
+        }
+    }
+}
+
+fn guild_prompt_dir(guild_id: GuildId) -> PathBuf {
+    Path::new(GUILD_PROMPT_DIR).join(guild_id.to_string())
+}
+
+/// The message a thread was started from; for a forum post it's the first message in the thread,
+/// otherwise it's the message in the parent channel with the same ID as the thread
+async fn thread_starter_message(cache: impl CacheHttp, channel: &Channel) -> Option<Message> {
+    let Channel::Guild(gc) = channel else { return None };
+    gc.thread_metadata?;
+    let message_id = MessageId::new(gc.id.get());
+
+    let msg = match gc.id.message(&cache, message_id).await {
+        Ok(msg) => msg,
+        Err(_) => gc.parent_id?.message(&cache, message_id).await.ok()?,
+    };
+    Some(msg).filter(|msg| !msg.content.trim().is_empty())
+}
+
+/// Make a message's text readable for the model, replacing mentions with names
+fn readable_content(cache: Option<&Arc<Cache>>, guild_id: Option<GuildId>, text: &str, mentions: &[User]) -> String {
+    markup::decode(text, |token| match token {
+        Token::User(id) => {
+            let id = UserId::new(id);
+            match mentions.iter().find(|u| u.id == id) {
+                Some(user) => Some(user.display_name().to_string()),
+                None => Some(cache?.user(id)?.display_name().to_string()),
+            }
+        }
+        Token::Role(id) => {
+            let guild = cache?.guild(guild_id?)?;
+            Some(guild.roles.get(&RoleId::new(id))?.name.clone())
+        }
+        Token::Channel(id) => {
+            let guild = cache?.guild(guild_id?)?;
+            Some(guild.channels.get(&ChannelId::new(id))?.name.clone())
+        }
+        Token::Emoji(_) => None,
+    })
+}
+
+/// Send each segment as a message, returning the IDs of the messages sent
+pub(crate) async fn send_segments(ctx: &Context, channel_id: ChannelId, segments: Vec<Segment>) -> serenity::Result<Vec<MessageId>> {
+    let mut message_ids = Vec::new();
+    for segment in segments {
+        let message = match segment {
+            Segment::Text(text) => CreateMessage::new().content(text),
+            Segment::Attachment { filename, content } => CreateMessage::new().add_file(CreateAttachment::bytes(content, filename)),
+        };
+        let sent = send_message(ctx, channel_id, message).await?;
+        message_ids.push(sent.id);
+    }
+    Ok(message_ids)
+}
+
+/// Send a message to a channel; every message the bot sends outside of a command goes through
+/// here, so none of them can mention roles or everyone
+async fn send_message(ctx: &Context, channel_id: ChannelId, message: CreateMessage) -> serenity::Result<Message> {
+    channel_id.send_message(ctx, message.allowed_mentions(markup::allowed_mentions())).await
 }
 
 // This seems to be Discord's limit; make our limit slightly smaller to allow to overhead
 const DISCORD_MAX_SEGMENT_SIZE: usize = 2000;
 const MAX_SEGMENT_SIZE: usize = DISCORD_MAX_SEGMENT_SIZE - 100;
 
-fn prepare_response(result: &str) -> Vec<String> {
+pub(crate) fn prepare_response(result: &str) -> Vec<Segment> {
     if result.len() < MAX_SEGMENT_SIZE {
-        return vec![result.to_string()];
+        return vec![Segment::Text(result.to_string())];
     }
 
-    let groups = crate::dialogue::split_result(result, MAX_SEGMENT_SIZE);
-    crate::dialogue::merge_groups(groups, MAX_SEGMENT_SIZE)
+    split_response(result, MAX_SEGMENT_SIZE)
 }
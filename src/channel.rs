@@ -1,17 +1,28 @@
+use std::collections::HashMap;
+
 use itertools::Itertools;
-use crate::dialogue::{Dialogue, MAXIMUM_DIALOGUE_LEN};
+use serenity::all::{GuildId, MessageId, User, UserId};
+
+use crate::dialogue::{Dialogue, Part, MAXIMUM_DIALOGUE_LEN};
+use crate::memory::{self, Memory, SharedMemory};
 use crate::prompt::Prompt;
+use crate::template::{expand, Variables};
 
 /// Channel mode; when does the bot respond to messages in a channel
-#[derive(Clone, Copy, Debug)]
+#[derive(Clone, Copy, Debug, poise::ChoiceParameter)]
 pub(crate) enum Mode {
     /// Ignores all non-command messages
+    #[name = "off"]
     Off,
-    /// Reads and responds only when mentioned
+    /// Reads and responds only when addressed (mentioned, replied to, or called by name)
+    #[name = "passive"]
     Passive,
-    /// Reads all but responds only when mentioned
+    /// Reads all but responds only when addressed
+    #[name = "lurking"]
+    #[name = "lurk"]
     Lurking,
     /// Reads and responds to all
+    #[name = "active"]
     Active,
 }
 
@@ -32,31 +43,149 @@ impl TryFrom<&str> for Mode {
     }
 }
 
+/// Words of the prompt that notes can't take up, so there's always room for the dialogue
+pub(crate) const DIALOGUE_RESERVE: u64 = 300;
+
 #[derive(Clone, Debug)]
 pub(crate) struct State {
     pub(crate) mode: Mode,
     pub(crate) prompt: Prompt,
     pub(crate) dialogue: Dialogue,
+    pub(crate) memory: Memory,
+    pub(crate) guild_id: Option<GuildId>,
+    pub(crate) guild_memory: Option<SharedMemory>,
+    /// Users seen in the channel, by name, so the model can mention them
+    pub(crate) known_users: HashMap<String, UserId>,
 }
 
 impl State {
-    pub(crate) fn process_user_text(&mut self, text: &str) {
-        self.dialogue.push("user", text);
+    pub(crate) fn new(mode: Mode, guild_memory: Option<SharedMemory>) -> State {
+        State {
+            mode,
+            prompt: Prompt::default(),
+            dialogue: Dialogue::new(),
+            memory: Memory::default(),
+            guild_id: None,
+            guild_memory,
+            known_users: HashMap::new(),
+        }
     }
 
-    pub(crate) fn process_model_text(&mut self, text: &str) {
-        self.dialogue.push("model", text);
+    pub(crate) fn learn_user(&mut self, user: &User) {
+        self.known_users.insert(user.display_name().to_string(), user.id);
+    }
+
+    pub(crate) fn process_user_text(&mut self, text: &str, message_id: MessageId) {
+        self.update_max_len();
+        self.dialogue.push_message("user", text, message_id);
+    }
+
+    pub(crate) fn process_model_text(&mut self, text: &str, message_ids: &[MessageId]) {
+        self.update_max_len();
+        self.dialogue.push_messages("model", text, message_ids);
+    }
+
+    pub(crate) fn process_edit(&mut self, message_id: MessageId, text: &str) -> bool {
+        self.dialogue.edit_message(message_id, text)
+    }
+
+    pub(crate) fn process_delete(&mut self, message_id: MessageId) -> bool {
+        self.dialogue.remove_message(message_id)
     }
 
     pub(crate) fn set_prompt(&mut self, prompt: &Prompt) {
-        self.dialogue.max_len = MAXIMUM_DIALOGUE_LEN - prompt.prompt.total_len;
-        self.dialogue.append(&prompt.initial);
         self.prompt = prompt.clone();
+        self.update_max_len();
+
+        let mut initial = prompt.initial.clone();
+        for part in &mut initial.parts {
+            part.template = true;
+        }
+        self.dialogue.append(&initial);
+    }
+
+    /// Replace the prompt with a new version of it, keeping the dialogue
+    pub(crate) fn update_prompt(&mut self, prompt: &Prompt) {
+        self.prompt = prompt.clone();
+        self.update_max_len();
     }
 
-    pub(crate) fn assemble_prompt(&self) -> Vec<(String, String)> {
+    /// Guild notes followed by this channel's notes
+    fn combined_memory(&self) -> Memory {
+        let mut memory = match &self.guild_memory {
+            Some(memory) => memory.lock().unwrap().clone(),
+            None => Memory::default(),
+        };
+        memory.notes.extend(self.memory.notes.iter().cloned());
+        memory
+    }
+
+    /// Check that there's room for another note, guild or channel, without cutting into the
+    /// dialogue's reserve
+    pub(crate) fn check_room_for_note(&self, note: &str) -> Result<(), memory::Error> {
+        let mut memory = self.combined_memory();
+        memory.notes.push(note.trim().to_string());
+        let fixed_len = self.prompt.prompt.total_len + memory.total_len();
+        if fixed_len + DIALOGUE_RESERVE > MAXIMUM_DIALOGUE_LEN {
+            return Err(memory::Error::NoRoom);
+        }
+        Ok(())
+    }
+
+    /// The dialogue gets whatever is left of the budget after the prompt and memory
+    pub(crate) fn update_max_len(&mut self) {
+        let fixed_len = self.prompt.prompt.total_len + self.combined_memory().total_len();
+        self.dialogue.set_max_len(MAXIMUM_DIALOGUE_LEN.saturating_sub(fixed_len));
+    }
+
+    /// The channel's prompt with its current dialogue as the initial dialogue
+    pub(crate) fn export_prompt(&self) -> Prompt {
+        Prompt {
+            prompt: self.prompt.prompt.clone(),
+            initial: self.dialogue.clone(),
+            filename: self.prompt.filename.clone(),
+            name: self.prompt.name.clone(),
+            metadata: self.prompt.metadata.clone(),
+        }
+    }
+
+    /// Replace the prompt and the dialogue with an imported one
+    pub(crate) fn import_prompt(&mut self, prompt: &Prompt) {
+        self.reset_dialogue();
+        self.set_prompt(prompt);
+    }
+
+    pub(crate) fn assemble_prompt(&self, variables: &Variables) -> Vec<(String, String)> {
         let mut prompt = Vec::new();
-        let combined_prompt = self.prompt.prompt.parts.iter().chain(self.dialogue.parts.iter());
+
+        /* Variables are only expanded in the prompt, memory and the prompt's initial dialogue,
+         * not in what users have said */
+        let expand_part = |part: &Part| {
+            let mut part = part.clone();
+            part.text = expand(&part.text, variables);
+            part
+        };
+        let preamble: Vec<Part> = self.prompt.prompt.parts.iter()
+            .chain(self.combined_memory().to_part().as_ref())
+            .map(expand_part)
+            .collect();
+        let mut dialogue: Vec<Part> = self.dialogue.parts.iter()
+            .map(|p| if p.template { expand_part(p) } else { p.clone() })
+            .collect();
+
+        /* Values can be longer than their placeholders, so leave out the oldest dialogue if the
+         * expanded text no longer fits */
+        let budget = MAXIMUM_DIALOGUE_LEN.saturating_sub(preamble.iter().map(Part::len).sum());
+        let mut dialogue_len: u64 = dialogue.iter().map(Part::len).sum();
+        /* The last part is what's being answered, so is always kept */
+        let mut num_dropped = 0;
+        while dialogue_len > budget && num_dropped + 1 < dialogue.len() {
+            dialogue_len -= dialogue[num_dropped].len();
+            num_dropped += 1;
+        }
+        dialogue.drain(..num_dropped);
+
+        let combined_prompt = preamble.iter().chain(dialogue.iter());
         for (key, group) in combined_prompt
                 .group_by(|p| &p.role).into_iter() {
             let text = group.map(|p| &p.text).join("\n\n");
@@ -65,6 +194,18 @@ impl State {
         prompt
     }
 
+    pub(crate) fn undo(&mut self, count: usize) -> usize {
+        self.dialogue.undo(count)
+    }
+
+    pub(crate) fn rewind_to(&mut self, message_id: MessageId) -> Option<usize> {
+        self.dialogue.rewind_to(message_id)
+    }
+
+    pub(crate) fn correct_last(&mut self, text: &str) -> bool {
+        self.dialogue.correct_last(text)
+    }
+
     pub(crate) fn reset_dialogue(&mut self) {
         self.dialogue.reset();
     }
@@ -76,17 +217,102 @@ mod test {
 
     #[test]
     fn test_assemble_prompt() {
-        let mut state = State { mode: Mode::Passive, prompt: Prompt::default(), dialogue: Dialogue::new() };
+        let mut state = State::new(Mode::Passive, None);
         state.dialogue.push("user", "ab");
         state.dialogue.push("user", "cd");
         state.dialogue.push("model", "ef");
         state.dialogue.push("model", "gh");
 
-        let prompt = state.assemble_prompt();
+        let prompt = state.assemble_prompt(&Variables::default());
         let expected: Vec<(String, String)> = vec![
             ("user".into(), "ab\n\ncd".into()),
             ("model".into(), "ef\n\ngh".into()),
         ];
         assert_eq!(expected, prompt);
     }
+
+    #[test]
+    fn test_assemble_prompt_with_memory() {
+        let guild_memory = SharedMemory::default();
+        guild_memory.lock().unwrap().remember("guild note").unwrap();
+        let mut state = State::new(Mode::Passive, Some(guild_memory));
+        state.prompt.prompt = Dialogue::new();
+        state.prompt.prompt.push("model", "prompt for {{user}}");
+        state.memory.remember("channel note").unwrap();
+        state.dialogue.push("model", "ab");
+
+        let variables = Variables { user: "Bob".to_string(), ..Variables::default() };
+        let prompt = state.assemble_prompt(&variables);
+        let expected: Vec<(String, String)> = vec![
+            ("model".into(), "prompt for Bob".into()),
+            ("user".into(), "Things to remember:\n- guild note\n- channel note\n".into()),
+            ("model".into(), "ab".into()),
+        ];
+        assert_eq!(expected, prompt);
+
+        state.update_max_len();
+        assert_eq!(MAXIMUM_DIALOGUE_LEN - 3 - 7, state.dialogue.max_len);
+    }
+
+    #[test]
+    fn test_assemble_prompt_initial_dialogue() {
+        let mut state = State::new(Mode::Passive, None);
+        let mut prompt = Prompt { initial: Dialogue::new(), ..Prompt::default() };
+        prompt.initial.push("model", "Hello {{user}}, it's {{now}}");
+        state.set_prompt(&prompt);
+        state.process_user_text("My name isn't {{user}}", MessageId::new(1));
+
+        let now = "word ".repeat(MAXIMUM_DIALOGUE_LEN as usize / 2);
+        let variables = Variables { user: "Bob".to_string(), now: now.clone(), ..Variables::default() };
+        let expected: Vec<(String, String)> = vec![
+            ("model".into(), format!("Hello Bob, it's {now}")),
+            ("user".into(), "My name isn't {{user}}".into()),
+        ];
+        assert_eq!(expected, state.assemble_prompt(&variables));
+
+        /* The oldest dialogue is left out if the expanded text is too long */
+        let now = "word ".repeat(MAXIMUM_DIALOGUE_LEN as usize);
+        let variables = Variables { now, ..variables };
+        let expected: Vec<(String, String)> = vec![("user".into(), "My name isn't {{user}}".into())];
+        assert_eq!(expected, state.assemble_prompt(&variables));
+    }
+
+    #[test]
+    fn test_notes_leave_room_for_dialogue() {
+        let mut state = State::new(Mode::Passive, Some(SharedMemory::default()));
+        state.prompt.prompt = Dialogue::new();
+        state.prompt.prompt.push("model", &"word ".repeat(500));
+
+        let note = "note ".repeat(40);
+        let mut num_notes = 0;
+        while state.check_room_for_note(&note).is_ok() {
+            state.memory.remember(&note).unwrap();
+            num_notes += 1;
+        }
+        assert_eq!(4, num_notes);
+        assert!(matches!(state.check_room_for_note(&note), Err(memory::Error::NoRoom)));
+
+        /* Even with guild notes on top, the message being answered is kept */
+        for _ in 0..10 {
+            state.guild_memory.as_ref().unwrap().lock().unwrap().remember(&note).unwrap();
+        }
+        state.process_user_text("hello there", MessageId::new(1));
+        let prompt = state.assemble_prompt(&Variables::default());
+        let (role, text) = prompt.last().unwrap();
+        assert_eq!("user", role);
+        assert!(text.ends_with("hello there"), "{text}");
+    }
+
+    #[test]
+    fn test_update_prompt_keeps_dialogue() {
+        let mut state = State::new(Mode::Passive, None);
+        state.dialogue.push("user", "hello there");
+
+        let mut prompt = Prompt { prompt: Dialogue::new(), ..Prompt::default() };
+        prompt.prompt.push("model", "one two three");
+        state.update_prompt(&prompt);
+
+        assert_eq!(1, state.dialogue.parts.len());
+        assert_eq!(MAXIMUM_DIALOGUE_LEN - 3, state.dialogue.max_len);
+    }
 }
@@ -3,13 +3,23 @@ use std::sync::Arc;
 
 use poise::builtins::HelpConfiguration;
 use poise::{CreateReply, serenity_prelude as serenity};
-use serenity::all::{CreateEmbed, InvalidToken, PartialGuild};
+use serenity::all::{Attachment, ChannelType, CreateAttachment, CreateEmbed, CreateThread, GuildId, Message, PartialGuild, Role};
 use serenity::framework::Framework;
 use serenity::utils::MessageBuilder;
 use tokio::sync::Mutex;
+use tracing::warn;
 
-use crate::bot::Bot;
+use crate::action::Action;
+use crate::bot::{prepare_response, send_segments, Bot, DEFAULT_THREAD_THRESHOLD, MAX_BACKFILL_LEN};
 use crate::channel::Mode;
+use crate::dialogue::MAXIMUM_DIALOGUE_LEN;
+use crate::markup;
+use crate::memory::{save_memory, CHANNEL_MEMORY_DIR, GUILD_MEMORY_DIR};
+use crate::overflow::Segment;
+use crate::permissions::{load_permissions, save_permissions, Capability, GUILD_PERMISSIONS_DIR};
+use crate::prompt::{parse_prompt, write_prompt, Catalogue, Prompt};
+use crate::store;
+use crate::store::{validate_name, PromptStore, MAX_PROMPT_SIZE};
 
 pub(crate) struct Data {
     bot: Arc<Mutex<Bot>>,
@@ -19,9 +29,24 @@ type Error = Box<dyn std::error::Error + Send + Sync>;
 pub(crate) type Context<'a> = poise::Context<'a, Data, Error>;
 type CommandResult = Result<(), Error>;
 
+/// Most prompts that can be uploaded to a guild
+const MAX_GUILD_PROMPTS: usize = 50;
+
+/// Longest description Discord allows for a slash command
+const MAX_DESCRIPTION_LEN: usize = 100;
+
+/// Longest name Discord allows for a slash or context menu command
+const MAX_COMMAND_NAME_LEN: usize = 32;
+
+/// Longest text Discord allows in an embed's description
+const MAX_EMBED_DESCRIPTION_LEN: usize = 4096;
+
+/// Shut the bot down
 #[poise::command(
     prefix_command,
-    category = "Admin"
+    slash_command,
+    category = "Admin",
+    owners_only,
 )]
 async fn shutdown(ctx: Context<'_>) -> CommandResult {
     system_message(ctx, "Shutting down").await?;
@@ -31,20 +56,40 @@ async fn shutdown(ctx: Context<'_>) -> CommandResult {
     Ok(())
 }
 
+/// Reload the prompt files, updating every channel that uses them
+#[poise::command(
+    prefix_command,
+    slash_command,
+    category = "Admin",
+    owners_only,
+)]
+async fn reload(ctx: Context<'_>) -> CommandResult {
+    let mut bot = ctx.data().bot.lock().await;
+    let num_updated = bot.reload_prompts().await?;
+
+    system_message(ctx, format!("Prompts reloaded; {num_updated} channels updated").as_str()).await?;
+
+    Ok(())
+}
+
+/// Show the bot's version
 #[poise::command(
     prefix_command,
+    slash_command,
     category = "General"
 )]
 async fn version(ctx: Context<'_>) -> CommandResult {
     const VERSION: &str = env!("CARGO_PKG_VERSION");
 
-    system_message(ctx, &format!("Clutha version {VERSION}")).await?;
+    ephemeral_message(ctx, &format!("Clutha version {VERSION}")).await?;
 
     Ok(())
 }
 
+/// Check that the bot is listening
 #[poise::command(
     prefix_command,
+    slash_command,
     category = "General"
 )]
 async fn ping(ctx: Context<'_>) -> CommandResult {
@@ -65,11 +110,17 @@ async fn ping(ctx: Context<'_>) -> CommandResult {
     Ok(())
 }
 
+/// Clear this channel's dialogue
 #[poise::command(
     prefix_command,
-    category = "General"
+    slash_command,
+    category = "General",
+    check = "check_reset",
 )]
 async fn reset(ctx: Context<'_>) -> CommandResult {
+    /* The channel's state may have to be created, which can mean reading its history */
+    ctx.defer().await?;
+
     let bot = ctx.data().bot.lock().await;
     let state = bot.channel_state(ctx, ctx.channel_id()).await?;
     state.lock().await.reset_dialogue();
@@ -79,11 +130,262 @@ async fn reset(ctx: Context<'_>) -> CommandResult {
     Ok(())
 }
 
+/// Remove the last exchanges from the dialogue
+#[poise::command(
+    prefix_command,
+    slash_command,
+    category = "General",
+    check = "check_reset",
+)]
+async fn undo(
+    ctx: Context<'_>,
+    #[description = "Number of exchanges to remove (default 1)"]
+    count: Option<usize>,
+) -> CommandResult {
+    ctx.defer().await?;
+
+    let bot = ctx.data().bot.lock().await;
+    let state = bot.channel_state(ctx, ctx.channel_id()).await?;
+    let removed = state.lock().await.undo(count.unwrap_or(1));
+
+    system_message(ctx, format!("Removed {removed} parts from the dialogue").as_str()).await?;
+
+    Ok(())
+}
+
+/// Remove a message and everything after it from the dialogue
+#[poise::command(
+    prefix_command,
+    slash_command,
+    category = "General",
+    check = "check_reset",
+)]
+async fn rewind(
+    ctx: Context<'_>,
+    #[description = "Link to or ID of the first message to remove"]
+    message: Message,
+) -> CommandResult {
+    ctx.defer().await?;
+
+    let bot = ctx.data().bot.lock().await;
+    let state = bot.channel_state(ctx, ctx.channel_id()).await?;
+    let removed = state.lock().await.rewind_to(message.id)
+        .ok_or("That message is not part of this channel's dialogue")?;
+
+    system_message(ctx, format!("Removed {removed} parts from the dialogue").as_str()).await?;
+
+    Ok(())
+}
+
+/// Replace the bot's last answer
+#[poise::command(
+    prefix_command,
+    slash_command,
+    category = "General",
+    check = "check_reset",
+)]
+async fn correct(
+    ctx: Context<'_>,
+    #[description = "What the answer should have been"]
+    #[rest]
+    text: String,
+) -> CommandResult {
+    ctx.defer().await?;
+
+    let bot = ctx.data().bot.lock().await;
+    let state = bot.channel_state(ctx, ctx.channel_id()).await?;
+    if !state.lock().await.correct_last(&text) {
+        return Err("There is no answer in the dialogue to correct".into());
+    }
+
+    system_message(ctx, "Last answer corrected").await?;
+
+    Ok(())
+}
+
+/// Copy this channel's dialogue into a new thread
 #[poise::command(
     prefix_command,
+    slash_command,
+    category = "General"
+)]
+async fn fork(
+    ctx: Context<'_>,
+    #[description = "Name of the thread (default is a suggested name)"]
+    name: Option<String>,
+    #[description = "Prompt for the thread (default is this channel's prompt)"]
+    #[autocomplete = "autocomplete_prompt"]
+    prompt_name: Option<String>,
+) -> CommandResult {
+    /* Anyone can fork, but giving the thread another prompt is the same as setting it */
+    if prompt_name.is_some() && !check_prompt(ctx).await? {
+        return Ok(());
+    }
+    ctx.defer().await?;
+
+    let mut bot = ctx.data().bot.lock().await;
+    let thread_id = bot.fork(ctx.serenity_context(), ctx.channel_id(), ctx.guild_id(), name, prompt_name.as_deref()).await?;
+
+    let response = MessageBuilder::new()
+        .push("Dialogue forked into ")
+        .channel(thread_id)
+        .build();
+    system_message(ctx, &response).await?;
+
+    Ok(())
+}
+
+/// Read recent messages in this channel into the dialogue
+#[poise::command(
+    prefix_command,
+    slash_command,
+    category = "General",
+    check = "check_reset",
+)]
+async fn backfill(
+    ctx: Context<'_>,
+    #[description = "Number of messages to read"]
+    count: Option<u8>,
+) -> CommandResult {
+    ctx.defer().await?;
+
+    let bot = ctx.data().bot.lock().await;
+    let state = bot.channel_state(ctx, ctx.channel_id()).await?;
+    let mut state = state.lock().await;
+
+    let count = count.unwrap_or(MAX_BACKFILL_LEN);
+    let num_read = bot.backfill(ctx, ctx.channel_id(), &mut state, count).await?;
+
+    system_message(ctx, format!("Dialogue backfilled from {num_read} messages").as_str()).await?;
+
+    Ok(())
+}
+
+/// Add a note for the bot to remember
+#[poise::command(
+    prefix_command,
+    slash_command,
+    category = "Memory",
+    check = "check_memory",
+)]
+async fn remember(
+    ctx: Context<'_>,
+    #[description = "Remember the note across the whole server"]
+    #[flag]
+    guild: bool,
+    #[description = "What to remember"]
+    #[rest]
+    note: String,
+) -> CommandResult {
+    ctx.defer().await?;
+
+    let bot = ctx.data().bot.lock().await;
+    let state = bot.channel_state(ctx, ctx.channel_id()).await?;
+    let mut state = state.lock().await;
+
+    state.check_room_for_note(&note)?;
+    if guild {
+        let guild_id = ctx.guild_id().ok_or("Server notes can only be added in a server")?;
+        let memory = bot.guild_memory(guild_id).await;
+        let memory = {
+            let mut memory = memory.lock().unwrap();
+            memory.remember(&note)?;
+            memory.clone()
+        };
+        save_memory(GUILD_MEMORY_DIR, guild_id, &memory)?;
+    } else {
+        state.memory.remember(&note)?;
+        save_memory(CHANNEL_MEMORY_DIR, ctx.channel_id(), &state.memory)?;
+    }
+    state.update_max_len();
+
+    system_message(ctx, "Noted").await?;
+
+    Ok(())
+}
+
+/// Remove a remembered note
+#[poise::command(
+    prefix_command,
+    slash_command,
+    category = "Memory",
+    check = "check_memory",
+)]
+async fn forget(
+    ctx: Context<'_>,
+    #[description = "Forget a server note rather than a channel note"]
+    #[flag]
+    guild: bool,
+    #[description = "Number of the note, as listed by memories"]
+    number: usize,
+) -> CommandResult {
+    ctx.defer().await?;
+
+    let bot = ctx.data().bot.lock().await;
+    let state = bot.channel_state(ctx, ctx.channel_id()).await?;
+    let mut state = state.lock().await;
+
+    let no_note = || format!("There is no note number {number}");
+    let forgotten = if guild {
+        let guild_id = ctx.guild_id().ok_or("Server notes can only be removed in a server")?;
+        let memory = bot.guild_memory(guild_id).await;
+        let (forgotten, memory) = {
+            let mut memory = memory.lock().unwrap();
+            (memory.forget(number).ok_or_else(no_note)?, memory.clone())
+        };
+        save_memory(GUILD_MEMORY_DIR, guild_id, &memory)?;
+        forgotten
+    } else {
+        let forgotten = state.memory.forget(number).ok_or_else(no_note)?;
+        save_memory(CHANNEL_MEMORY_DIR, ctx.channel_id(), &state.memory)?;
+        forgotten
+    };
+    state.update_max_len();
+
+    system_message(ctx, format!("Forgot *{forgotten}*").as_str()).await?;
+
+    Ok(())
+}
+
+/// List the notes the bot remembers
+#[poise::command(
+    prefix_command,
+    slash_command,
+    category = "Memory"
+)]
+async fn memories(ctx: Context<'_>) -> CommandResult {
+    ctx.defer_ephemeral().await?;
+
+    let bot = ctx.data().bot.lock().await;
+    let state = bot.channel_state(ctx, ctx.channel_id()).await?;
+    let state = state.lock().await;
+
+    let mut embed = CreateEmbed::new()
+        .field("Channel notes", none_if_empty(state.memory.list()), false);
+    if let Some(guild_memory) = &state.guild_memory {
+        let guild_notes = guild_memory.lock().unwrap().list();
+        embed = embed.field("Server notes", none_if_empty(guild_notes), false);
+    }
+
+    let builder = CreateReply::default().embed(embed).ephemeral(true);
+    ctx.send(builder).await?;
+
+    Ok(())
+}
+
+fn none_if_empty(text: String) -> String {
+    if text.is_empty() { "(none)".to_string() } else { text }
+}
+
+/// Show this channel's mode, prompt and dialogue size
+#[poise::command(
+    prefix_command,
+    slash_command,
     category = "General"
 )]
 async fn info(ctx: Context<'_>) -> CommandResult {
+    ctx.defer_ephemeral().await?;
+
     let bot = ctx.data().bot.lock().await;
     let state = bot.channel_state(ctx, ctx.channel_id()).await?;
     let state = state.lock().await;
@@ -107,9 +409,13 @@ async fn info(ctx: Context<'_>) -> CommandResult {
     };
 
     let mode_str = format!("{:?}", state.mode);
-    let prompt_str = &state.prompt.filename;
+    let metadata = &state.prompt.metadata;
+    let prompt_str = match &metadata.name {
+        Some(name) => format!("{name} ({})", state.prompt.filename),
+        None => state.prompt.filename.clone(),
+    };
 
-    let embed = CreateEmbed::new()
+    let mut embed = CreateEmbed::new()
         .description(context.build())
         .field("Mode", mode_str, true)
         .field("Prompt", prompt_str, true)
@@ -120,79 +426,686 @@ async fn info(ctx: Context<'_>) -> CommandResult {
             true,
         );
 
-    let builder = CreateReply::default().embed(embed);
+    let generation = &metadata.generation;
+    let optional_fields = [
+        ("Description", metadata.description.clone()),
+        ("Author", metadata.author.clone()),
+        ("Default mode", metadata.mode.map(|m| format!("{m:?}"))),
+        ("Backend", metadata.backend.clone()),
+        ("Model", generation.model.clone()),
+        ("Temperature", generation.temperature.map(|v| v.to_string())),
+        ("Top P", generation.top_p.map(|v| v.to_string())),
+        ("Max output tokens", generation.max_output_tokens.map(|v| v.to_string())),
+        ("Thread threshold", metadata.thread_threshold.map(|v| v.to_string())),
+    ];
+    for (name, value) in optional_fields {
+        if let Some(value) = value {
+            embed = embed.field(name, value, true);
+        }
+    }
+
+    let builder = CreateReply::default().embed(embed).ephemeral(true);
     ctx.send(builder).await?;
 
     Ok(())
 }
 
+/// Set when the bot responds in this channel
 #[poise::command(
     prefix_command,
+    slash_command,
     category = "General",
+    check = "check_mode",
 )]
-async fn mode(ctx: Context<'_>, mode: String) -> CommandResult {
+async fn mode(
+    ctx: Context<'_>,
+    #[description = "When the bot reads and responds to messages"]
+    mode: Mode,
+) -> CommandResult {
+    ctx.defer().await?;
+
     let bot = ctx.data().bot.lock().await;
     let state = bot.channel_state(ctx, ctx.channel_id()).await?;
-    let mut state = state.lock().await;
-
-    let new_mode: Mode = mode.as_str().try_into()
-        .map_err(|_| InvalidToken)?;
+    state.lock().await.mode = mode;
 
-    state.mode = new_mode;
-
-    system_message(ctx, format!("Mode set to *{new_mode:?}*").as_str()).await?;
+    system_message(ctx, format!("Mode set to *{mode:?}*").as_str()).await?;
 
     Ok(())
 }
 
 async fn prompt_command(ctx: Context<'_>, prompt_name: String) -> CommandResult {
+    /* Setting a prompt can need a response from the model, which takes a while */
+    ctx.defer().await?;
+
     let mut bot = ctx.data().bot.lock().await;
-    let needs_response = bot.set_prompt(ctx.serenity_context(), ctx.channel_id(), prompt_name.as_str()).await?;
+    let prompt = bot.set_prompt(ctx.serenity_context(), ctx.channel_id(), ctx.guild_id(), prompt_name.as_str()).await?;
 
-    system_message(ctx, format!("Prompt set to *{prompt_name}*").as_str()).await?;
+    let mut message = format!("Prompt set to *{prompt_name}*");
+    let unknown = prompt.unknown_variables();
+    if !unknown.is_empty() {
+        message.push_str(&format!("\nUnknown variables in prompt: {}", unknown.join(", ")));
+    }
+    system_message(ctx, &message).await?;
 
-    if needs_response {
+    if prompt.needs_response() {
         bot.do_ai_response(ctx.serenity_context(), ctx.channel_id(), None).await?;
     }
 
     Ok(())
 }
 
+/// Sets a prompt that has its own command; the prompt name is the command's custom data
 #[poise::command(
     prefix_command,
-    category = "Prompt"
+    slash_command,
+    category = "Prompt",
+    check = "check_prompt",
+)]
+async fn prompt_shortcut(ctx: Context<'_>) -> CommandResult {
+    let prompt_name = ctx.command().custom_data.downcast_ref::<String>()
+        .ok_or("Prompt command has no prompt")?
+        .clone();
+    prompt_command(ctx, prompt_name).await
+}
+
+/// A command for each prompt that asks for one; Discord rejects every command if any has a name
+/// it doesn't allow, so names are made lower case and prompts with names that are too long, or
+/// the same as another's, are left out
+fn shortcut_commands(catalogue: &Catalogue) -> Vec<poise::Command<Data, Error>> {
+    let mut commands: Vec<poise::Command<Data, Error>> = Vec::new();
+    for (name, prompt) in catalogue.commands() {
+        let command_name = name.to_lowercase();
+        if command_name.len() > MAX_COMMAND_NAME_LEN {
+            warn!("Prompt {name} has a name too long for a command");
+            continue;
+        }
+        if commands.iter().any(|c| c.name == command_name) {
+            warn!("Prompt {name} has the same command name as another prompt");
+            continue;
+        }
+
+        let mut command = prompt_shortcut();
+        command.name = command_name.clone();
+        command.qualified_name = command_name.clone();
+        command.identifying_name = command_name.clone();
+        /* The prompt's own name still works as a prefix command */
+        if command_name != name {
+            command.aliases = vec![name.to_string()];
+        }
+        let description = prompt.metadata.description.clone()
+            .unwrap_or_else(|| format!("Set the {name} prompt"));
+        command.description = Some(description.chars().take(MAX_DESCRIPTION_LEN).collect());
+        command.custom_data = Box::new(name.to_string());
+        commands.push(command);
+    }
+    commands
+}
+
+async fn autocomplete_prompt(ctx: Context<'_>, partial: &str) -> Vec<String> {
+    let bot = ctx.data().bot.lock().await;
+    let mut names: Vec<String> = bot.catalogue.matching(partial).map(str::to_string).collect();
+    names.extend(guild_prompt_names(&bot, ctx.guild_id()).into_iter().filter(|n| n.starts_with(partial)));
+    names.sort();
+    names.dedup();
+    names
+}
+
+/// Names of the prompts uploaded to the guild, if any
+fn guild_prompt_names(bot: &Bot, guild_id: Option<GuildId>) -> Vec<String> {
+    guild_id
+        .and_then(|g| bot.guild_prompt_store(g).ok())
+        .and_then(|store| store.names().ok())
+        .unwrap_or_default()
+}
+
+/// Set this channel's prompt
+#[poise::command(
+    prefix_command,
+    slash_command,
+    category = "Prompt",
+    subcommands("prompt_set", "prompt_upload", "prompt_list", "prompt_delete"),
+)]
+async fn prompt(
+    ctx: Context<'_>,
+    /* Only `~prompt <name>` gets here, as slash commands can't run a command with subcommands,
+       so the name isn't autocompleted */
+    #[description = "Name of the prompt"]
+    prompt_name: String,
+) -> CommandResult {
+    /* Checks on a parent command also apply to its subcommands, which have their own */
+    if !check_prompt(ctx).await? {
+        return Ok(());
+    }
+    prompt_command(ctx, prompt_name).await
+}
+
+/// Set this channel's prompt
+///
+/// The same as `~prompt <name>`, which slash commands can't do as `/prompt` has subcommands.
+#[poise::command(
+    prefix_command,
+    slash_command,
+    rename = "set",
+    category = "Prompt",
+    check = "check_prompt",
 )]
-async fn default(ctx: Context<'_>) -> CommandResult {
-    prompt_command(ctx, "default".to_string()).await
+async fn prompt_set(
+    ctx: Context<'_>,
+    #[description = "Name of the prompt"]
+    #[autocomplete = "autocomplete_prompt"]
+    prompt_name: String,
+) -> CommandResult {
+    prompt_command(ctx, prompt_name).await
 }
 
+/// Upload a prompt file for use on this server only
 #[poise::command(
     prefix_command,
+    slash_command,
+    rename = "upload",
+    category = "Prompt",
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+)]
+async fn prompt_upload(
+    ctx: Context<'_>,
+    #[description = "Name to give the prompt"]
+    prompt_name: String,
+    #[description = "The prompt file"]
+    file: Attachment,
+) -> CommandResult {
+    validate_name(&prompt_name)?;
+    if is_prompt_subcommand(&prompt_name) {
+        return Err(format!("A prompt can't be called '{prompt_name}', as `~prompt {prompt_name}` is another command").into());
+    }
+    let fragments = ctx.data().bot.lock().await.prompt_store.fragments().cloned();
+    let (text, mut prompt) = read_prompt_attachment(&file, fragments.as_ref()).await?;
+    let ignored = ignored_settings_note(&mut prompt);
+
+    let bot = ctx.data().bot.lock().await;
+    let store = bot.create_guild_prompt_store(ctx.guild_id().ok_or("Not in a server")?)?;
+    if store.names()?.len() >= MAX_GUILD_PROMPTS && store.load(&prompt_name).is_err() {
+        return Err(format!("This server already has {MAX_GUILD_PROMPTS} prompts").into());
+    }
+    store.save(&prompt_name, &text)?;
+
+    system_message(ctx, format!("Prompt *{prompt_name}* uploaded{ignored}").as_str()).await?;
+
+    Ok(())
+}
+
+/// Whether a name is taken by one of `prompt`'s subcommands, so a prompt with it couldn't be set
+/// with `~prompt <name>`
+fn is_prompt_subcommand(name: &str) -> bool {
+    prompt().subcommands.iter()
+        .flat_map(|c| std::iter::once(&c.name).chain(&c.aliases))
+        .any(|n| n.eq_ignore_ascii_case(name))
+}
+
+/// List the prompts uploaded to this server
+#[poise::command(
+    prefix_command,
+    slash_command,
+    rename = "list",
+    category = "Prompt",
+    guild_only,
+)]
+async fn prompt_list(ctx: Context<'_>) -> CommandResult {
+    let bot = ctx.data().bot.lock().await;
+    let names = guild_prompt_names(&bot, ctx.guild_id());
+
+    let text = if names.is_empty() {
+        "No prompts have been uploaded to this server".to_string()
+    } else {
+        names.iter().map(|n| format!("**{n}**\n")).collect()
+    };
+    ephemeral_message(ctx, &text).await?;
+
+    Ok(())
+}
+
+/// Delete a prompt uploaded to this server
+#[poise::command(
+    prefix_command,
+    slash_command,
+    rename = "delete",
+    category = "Prompt",
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+)]
+async fn prompt_delete(
+    ctx: Context<'_>,
+    #[description = "Name of the prompt"]
+    prompt_name: String,
+) -> CommandResult {
+    let bot = ctx.data().bot.lock().await;
+    let store = bot.guild_prompt_store(ctx.guild_id().ok_or("Not in a server")?)
+        .map_err(|_| store::Error::NotFound(prompt_name.clone()))?;
+    store.delete(&prompt_name)?;
+
+    system_message(ctx, format!("Prompt *{prompt_name}* deleted").as_str()).await?;
+
+    Ok(())
+}
+
+/// List the available prompts
+#[poise::command(
+    prefix_command,
+    slash_command,
     category = "Prompt"
 )]
-async fn about(ctx: Context<'_>) -> CommandResult {
-    prompt_command(ctx, "about".to_string()).await
+async fn prompts(ctx: Context<'_>) -> CommandResult {
+    let bot = ctx.data().bot.lock().await;
+
+    let mut list = MessageBuilder::new();
+    for (name, prompt) in &bot.catalogue.prompts {
+        list.push_bold_safe(name);
+        if let Some(description) = &prompt.metadata.description {
+            list.push(" - ").push_safe(description);
+        }
+        list.push("\n");
+    }
+
+    let guild_names = guild_prompt_names(&bot, ctx.guild_id());
+    if !guild_names.is_empty() {
+        list.push("\nUploaded to this server:\n");
+        for name in guild_names {
+            list.push_bold_safe(&name);
+            if let Ok(prompt) = bot.load_prompt(ctx.guild_id(), &name) {
+                if let Some(description) = &prompt.metadata.description {
+                    list.push(" - ").push_safe(description);
+                }
+            }
+            list.push("\n");
+        }
+    }
+
+    /* A long list is sent as several messages, as an embed can only hold so much */
+    for page in paginate(&list.build(), MAX_EMBED_DESCRIPTION_LEN) {
+        ephemeral_message(ctx, &page).await?;
+    }
+
+    Ok(())
+}
+
+/// Split text into pages of at most `max_len` bytes, between lines; a line too long for a page of
+/// its own is cut short
+fn paginate(text: &str, max_len: usize) -> Vec<String> {
+    let mut pages = Vec::new();
+    let mut page = String::new();
+    for line in text.lines() {
+        let mut line = line;
+        if line.len() > max_len {
+            let mut end = max_len;
+            while !line.is_char_boundary(end) {
+                end -= 1;
+            }
+            line = &line[..end];
+        }
+        if !page.is_empty() && page.len() + 1 + line.len() > max_len {
+            pages.push(std::mem::take(&mut page));
+        }
+        if !page.is_empty() {
+            page.push('\n');
+        }
+        page.push_str(line);
+    }
+    if !page.trim().is_empty() {
+        pages.push(page);
+    }
+    pages
+}
+
+/// Ask the bot about a message, and the conversation leading up to it
+#[poise::command(
+    context_menu_command = "Ask Clutha about this",
+    category = "Actions",
+)]
+async fn ask_about(ctx: Context<'_>, msg: Message) -> CommandResult {
+    action_command(ctx, Action::Ask, msg).await
+}
+
+/// Summarize the conversation from a message onwards
+#[poise::command(
+    context_menu_command = "Summarize from here",
+    category = "Actions",
+)]
+async fn summarize_from(ctx: Context<'_>, msg: Message) -> CommandResult {
+    action_command(ctx, Action::Summarize, msg).await
+}
+
+/// Explain the code in a message
+#[poise::command(
+    context_menu_command = "Explain this code",
+    category = "Actions",
+)]
+async fn explain_code(ctx: Context<'_>, msg: Message) -> CommandResult {
+    action_command(ctx, Action::Explain, msg).await
+}
+
+/// Run an action on a message; the answer is only shown to the user who asked, unless it's long
+/// enough to go in a thread on the message
+async fn action_command(ctx: Context<'_>, action: Action, msg: Message) -> CommandResult {
+    ctx.defer_ephemeral().await?;
+
+    let bot = ctx.data().bot.lock().await;
+    let prompt = bot.load_action_prompt(action)?;
+    let answer = bot.run_action(ctx.serenity_context(), action, &prompt, &msg, ctx.guild_id(), ctx.author()).await?;
+    drop(bot);
+
+    let segments = prepare_response(&answer);
+
+    /* Threads can only be started from messages in text channels that don't have one already */
+    let kind = msg.channel_id.to_channel(ctx).await?.guild().map(|c| c.kind);
+    let can_thread = matches!(kind, Some(ChannelType::Text | ChannelType::News)) && msg.thread.is_none();
+    let thread_threshold = prompt.metadata.thread_threshold.unwrap_or(DEFAULT_THREAD_THRESHOLD);
+
+    if can_thread && answer.len() > thread_threshold {
+        let builder = CreateThread::new(action.thread_name());
+        let thread_id = msg.channel_id.create_thread_from_message(ctx, msg.id, builder).await?.id;
+        send_segments(ctx.serenity_context(), thread_id, segments).await?;
+
+        let response = MessageBuilder::new().push("Answered in ").channel(thread_id).build();
+        ephemeral_message(ctx, &response).await?;
+    } else {
+        for segment in segments {
+            let reply = match segment {
+                Segment::Text(text) => CreateReply::default().content(text),
+                Segment::Attachment { filename, content } => CreateReply::default().attachment(CreateAttachment::bytes(content, filename)),
+            };
+            ctx.send(reply.ephemeral(true)).await?;
+        }
+    }
+
+    Ok(())
+}
+
+/// Show which roles can change the bot's settings in this server
+///
+/// Anyone can do something that no roles have been given.  Members who can manage the server can
+/// always do everything.
+#[poise::command(
+    prefix_command,
+    slash_command,
+    category = "Admin",
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+    subcommands("permissions_list", "permissions_grant", "permissions_revoke"),
+)]
+async fn permissions(ctx: Context<'_>) -> CommandResult {
+    permissions_command(ctx).await
+}
+
+/// Show which roles can change the bot's settings in this server
+#[poise::command(
+    prefix_command,
+    slash_command,
+    rename = "list",
+    category = "Admin",
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+)]
+async fn permissions_list(ctx: Context<'_>) -> CommandResult {
+    permissions_command(ctx).await
+}
+
+async fn permissions_command(ctx: Context<'_>) -> CommandResult {
+    let guild_id = ctx.guild_id().ok_or("Not in a server")?;
+    let permissions = load_permissions(GUILD_PERMISSIONS_DIR, guild_id)?;
+
+    let mut list = MessageBuilder::new();
+    for capability in Capability::ALL {
+        list.push_bold(capability.to_string()).push(": ");
+        let roles = permissions.roles(capability);
+        if roles.is_empty() {
+            list.push("anyone");
+        }
+        for (i, role_id) in roles.into_iter().enumerate() {
+            if i > 0 {
+                list.push(", ");
+            }
+            list.role(role_id);
+        }
+        list.push("\n");
+    }
+    ephemeral_message(ctx, &list.build()).await?;
+
+    Ok(())
+}
+
+/// Let members with a role do something
+#[poise::command(
+    prefix_command,
+    slash_command,
+    rename = "grant",
+    category = "Admin",
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+)]
+async fn permissions_grant(
+    ctx: Context<'_>,
+    #[description = "What members with the role can do"]
+    capability: Capability,
+    #[description = "The role"]
+    role: Role,
+) -> CommandResult {
+    let guild_id = ctx.guild_id().ok_or("Not in a server")?;
+    let mut permissions = load_permissions(GUILD_PERMISSIONS_DIR, guild_id)?;
+    permissions.grant(capability, role.id);
+    save_permissions(GUILD_PERMISSIONS_DIR, guild_id, &permissions)?;
+
+    let response = MessageBuilder::new()
+        .push("Members with ")
+        .role(role.id)
+        .push(format!(" can now use *{capability}* commands"))
+        .build();
+    system_message(ctx, &response).await?;
+
+    Ok(())
+}
+
+/// Stop members with a role doing something, unless they have another role that can
+#[poise::command(
+    prefix_command,
+    slash_command,
+    rename = "revoke",
+    category = "Admin",
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+)]
+async fn permissions_revoke(
+    ctx: Context<'_>,
+    #[description = "What members with the role can no longer do"]
+    capability: Capability,
+    #[description = "The role"]
+    role: Role,
+) -> CommandResult {
+    let guild_id = ctx.guild_id().ok_or("Not in a server")?;
+    let mut permissions = load_permissions(GUILD_PERMISSIONS_DIR, guild_id)?;
+    if !permissions.revoke(capability, role.id) {
+        return Err(format!("That role wasn't given *{capability}*").into());
+    }
+    save_permissions(GUILD_PERMISSIONS_DIR, guild_id, &permissions)?;
+
+    let mut response = MessageBuilder::new();
+    response.push("Members with ").role(role.id).push(format!(" can no longer use *{capability}* commands"));
+    if permissions.roles(capability).is_empty() {
+        response.push(format!("; as no roles have *{capability}*, anyone can use them"));
+    }
+    system_message(ctx, &response.build()).await?;
+
+    Ok(())
 }
 
+/// Show how often users approved of responses in this server, for each prompt and model
+///
+/// Approval is counted from 👍 and 👎 reactions to the bot's responses.
 #[poise::command(
     prefix_command,
+    slash_command,
+    category = "Admin",
+    guild_only,
+    required_permissions = "MANAGE_GUILD",
+)]
+async fn feedback(ctx: Context<'_>) -> CommandResult {
+    let guild_id = ctx.guild_id().ok_or("Not in a server")?;
+    let report = ctx.data().bot.lock().await.feedback.report(Some(guild_id));
+    if report.is_empty() {
+        return Err("There are no responses in this server yet".into());
+    }
+
+    let mut list = MessageBuilder::new();
+    for approval in report {
+        list.push_bold_safe(&approval.prompt)
+            .push(format!(" ({} {}): ", approval.backend, approval.model));
+        match approval.rate() {
+            Some(rate) => list.push(format!("{:.0}% of {} votes", rate * 100.0, approval.up + approval.down)),
+            None => list.push("no votes"),
+        };
+        list.push(format!(" on {} responses\n", approval.responses));
+    }
+    ephemeral_message(ctx, &list.build()).await?;
+
+    Ok(())
+}
+
+/// Whether the author can use commands needing the capability; if not, they're told why
+async fn check_capability(ctx: Context<'_>, capability: Capability) -> Result<bool, Error> {
+    let Some(guild_id) = ctx.guild_id() else {
+        return Ok(true);
+    };
+    if ctx.framework().options().owners.contains(&ctx.author().id) {
+        return Ok(true);
+    }
+    let Some(member) = ctx.author_member().await else {
+        return Ok(false);
+    };
+    /* The guild may not be cached yet, in which case it's fetched */
+    let cached = ctx.guild().map(|g| g.member_permissions(&member));
+    let member_permissions = match cached {
+        Some(member_permissions) => member_permissions,
+        None => guild_id.to_partial_guild(ctx).await?.member_permissions(&member),
+    };
+    if member_permissions.manage_guild() {
+        return Ok(true);
+    }
+
+    let permissions = load_permissions(GUILD_PERMISSIONS_DIR, guild_id)?;
+    if permissions.allows(capability, &member.roles) {
+        return Ok(true);
+    }
+
+    ephemeral_message(ctx, &format!("You don't have a role that can use *{capability}* commands here")).await?;
+    Ok(false)
+}
+
+async fn check_mode(ctx: Context<'_>) -> Result<bool, Error> {
+    check_capability(ctx, Capability::Mode).await
+}
+
+async fn check_prompt(ctx: Context<'_>) -> Result<bool, Error> {
+    check_capability(ctx, Capability::Prompt).await
+}
+
+async fn check_reset(ctx: Context<'_>) -> Result<bool, Error> {
+    check_capability(ctx, Capability::Reset).await
+}
+
+async fn check_memory(ctx: Context<'_>) -> Result<bool, Error> {
+    check_capability(ctx, Capability::Memory).await
+}
+
+/// Download a prompt file and check that it's usable, returning its text and the parsed prompt
+async fn read_prompt_attachment(file: &Attachment, fragments: Option<&PromptStore>) -> Result<(String, Prompt), Error> {
+    if u64::from(file.size) > MAX_PROMPT_SIZE {
+        return Err(format!("Prompt file is too large ({} bytes, maximum is {MAX_PROMPT_SIZE})", file.size).into());
+    }
+
+    let bytes = file.download().await?;
+    let text = String::from_utf8(bytes).map_err(|_| "Prompt file is not valid UTF-8 text")?;
+    let prompt = parse_prompt(&text, &file.filename, fragments)?;
+
+    if prompt.prompt.parts.is_empty() && prompt.initial.parts.is_empty() {
+        return Err("Prompt file contains no dialogue".into());
+    }
+    if prompt.prompt.total_len >= MAXIMUM_DIALOGUE_LEN {
+        return Err(format!("Prompt is too long ({} words, maximum is {MAXIMUM_DIALOGUE_LEN})", prompt.prompt.total_len).into());
+    }
+
+    Ok((text, prompt))
+}
+
+/// Drop the backend settings of a prompt from a user, which only the operator can choose; returns
+/// a note for the user if there were any
+fn ignored_settings_note(prompt: &mut Prompt) -> &'static str {
+    if prompt.drop_backend_settings() {
+        " (its backend, model and generation settings are ignored)"
+    } else {
+        ""
+    }
+}
+
+/// Download this channel's prompt and dialogue as a prompt file
+#[poise::command(
+    prefix_command,
+    slash_command,
     category = "Prompt"
 )]
-async fn prompt(ctx: Context<'_>, prompt_name: String) -> CommandResult {
-    prompt_command(ctx, prompt_name).await
+async fn export(ctx: Context<'_>) -> CommandResult {
+    ctx.defer().await?;
+
+    let bot = ctx.data().bot.lock().await;
+    let state = bot.channel_state(ctx, ctx.channel_id()).await?;
+    let text = write_prompt(&state.lock().await.export_prompt());
+
+    let attachment = CreateAttachment::bytes(text.into_bytes(), "dialogue.txt");
+    let builder = CreateReply::default().attachment(attachment);
+    ctx.send(builder).await?;
+
+    Ok(())
+}
+
+/// Replace this channel's prompt and dialogue with a prompt file
+#[poise::command(
+    prefix_command,
+    slash_command,
+    category = "Prompt",
+    check = "check_prompt",
+)]
+async fn import(
+    ctx: Context<'_>,
+    #[description = "A prompt file, such as one from export"]
+    file: Attachment,
+) -> CommandResult {
+    ctx.defer().await?;
+
+    let fragments = ctx.data().bot.lock().await.prompt_store.fragments().cloned();
+    let (_, mut prompt) = read_prompt_attachment(&file, fragments.as_ref()).await?;
+    let ignored = ignored_settings_note(&mut prompt);
+
+    let bot = ctx.data().bot.lock().await;
+    let state = bot.channel_state(ctx, ctx.channel_id()).await?;
+    state.lock().await.import_prompt(&prompt);
+
+    system_message(ctx, format!("Imported prompt *{}*{ignored}", file.filename).as_str()).await?;
+
+    Ok(())
 }
 
+/// Show help for the bot's commands
 #[poise::command(
     prefix_command,
+    slash_command,
     category = "General"
 )]
 async fn help(
     ctx: Context<'_>,
+    #[description = "Command to show help for"]
     #[rest]
     command: Option<String>,
 ) -> CommandResult {
     let extra_text_at_bottom = "\
-Type `~help command` for more info on a command.
+Type `~help command` or `/help command` for more info on a command.
 You can edit your `~help` message to the bot and the bot will edit its response.";
 
     let config = HelpConfiguration {
@@ -214,27 +1127,68 @@ pub(crate) async fn system_message(ctx: Context<'_>, text: &str) -> CommandResul
     Ok(())
 }
 
-pub fn create_framework(bot: Arc<Mutex<Bot>>) -> Result<impl Framework, serenity::Error> {
+/// A system message that, for slash commands, only the user who ran the command can see
+async fn ephemeral_message(ctx: Context<'_>, text: &str) -> CommandResult {
+    let embed = CreateEmbed::new().description(text);
+    let reply = CreateReply::default().embed(embed).ephemeral(true);
+    ctx.send(reply).await?;
+    Ok(())
+}
+
+/// The built-in commands
+fn builtin_commands() -> Vec<poise::Command<Data, Error>> {
+    vec![
+        shutdown(),
+        reload(),
+        version(),
+        ping(),
+        reset(),
+        undo(),
+        rewind(),
+        correct(),
+        fork(),
+        backfill(),
+        info(),
+        mode(),
+        help(),
+        prompt(),
+        prompts(),
+        export(),
+        import(),
+        remember(),
+        forget(),
+        memories(),
+        permissions(),
+        feedback(),
+        ask_about(),
+        summarize_from(),
+        explain_code(),
+    ]
+}
+
+pub fn create_framework(bot: Arc<Mutex<Bot>>, catalogue: &Catalogue) -> Result<impl Framework, serenity::Error> {
     let data = Data { bot };
 
+    let mut commands = builtin_commands();
+
+    /* Prompt commands can't replace the built-in ones */
+    for command in shortcut_commands(catalogue) {
+        if commands.iter().any(|c| c.name == command.name) {
+            warn!("Prompt {} has the same name as a command", command.name);
+            continue;
+        }
+        commands.push(command);
+    }
+
     let framework = poise::Framework::builder()
         .options(poise::FrameworkOptions {
-            commands: vec![
-                shutdown(),
-                version(),
-                ping(),
-                reset(),
-                info(),
-                mode(),
-                help(),
-                default(),
-                about(),
-                prompt(),
-            ],
+            commands,
             prefix_options: poise::PrefixFrameworkOptions {
                 prefix: Some("~".to_string()),
                 ..Default::default()
             },
+            /* Every reply, including error messages, mentions at most users */
+            allowed_mentions: Some(markup::allowed_mentions()),
             ..Default::default()
         })
         .setup(|ctx, _ready, framework| {
@@ -247,3 +1201,68 @@ pub fn create_framework(bot: Arc<Mutex<Bot>>) -> Result<impl Framework, serenity
 
     Ok(framework)
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    /// Discord rejects slash commands without short descriptions and lower case names
+    fn check_slash_command(command: &poise::Command<Data, Error>) {
+        let name = &command.qualified_name;
+        assert_eq!(command.name.to_lowercase(), command.name, "{name}");
+        let description = command.description.as_deref().unwrap_or_default();
+        assert!(!description.is_empty() && description.len() <= MAX_DESCRIPTION_LEN, "{name}: {description:?}");
+        for parameter in &command.parameters {
+            assert!(parameter.description.is_some(), "{name} {}", parameter.name);
+        }
+        command.subcommands.iter().for_each(check_slash_command);
+    }
+
+    #[test]
+    fn test_is_prompt_subcommand() {
+        for name in ["upload", "list", "delete", "set", "Set"] {
+            assert!(is_prompt_subcommand(name), "{name}");
+        }
+        assert!(!is_prompt_subcommand("default"));
+    }
+
+    #[test]
+    fn test_paginate() {
+        assert_eq!(vec!["one\ntwo", "three"], paginate("one\ntwo\nthree\n", 8));
+        assert_eq!(vec!["one", "four"], paginate("one\nfourteen\n", 4));
+        assert_eq!(vec!["é"], paginate("éé", 3));
+        assert!(paginate("", 8).is_empty());
+    }
+
+    #[test]
+    fn test_slash_commands() {
+        for command in builtin_commands() {
+            if let Some(name) = &command.context_menu_name {
+                assert!(command.context_menu_action.is_some(), "{name}");
+                assert!(name.len() <= MAX_COMMAND_NAME_LEN, "{name}");
+                continue;
+            }
+            assert!(command.slash_action.is_some(), "{}", command.name);
+            check_slash_command(&command);
+        }
+    }
+
+    #[test]
+    fn test_shortcut_commands() {
+        let mut catalogue = Catalogue::default();
+        let mut prompt = Prompt::default();
+        prompt.metadata.command = true;
+        prompt.metadata.description = Some("x".repeat(200));
+        catalogue.prompts.insert("long".to_string(), prompt.clone());
+        catalogue.prompts.insert("Upper".to_string(), prompt.clone());
+        catalogue.prompts.insert("upper".to_string(), prompt.clone());
+        catalogue.prompts.insert("x".repeat(MAX_COMMAND_NAME_LEN + 1), prompt);
+
+        let commands = shortcut_commands(&catalogue);
+        assert_eq!(vec!["upper", "long"], commands.iter().map(|c| c.name.as_str()).collect::<Vec<_>>());
+        assert_eq!(vec!["Upper"], commands[0].aliases);
+        assert_eq!(Some(&"Upper".to_string()), commands[0].custom_data.downcast_ref::<String>());
+        assert_eq!(MAX_DESCRIPTION_LEN, commands[1].description.as_ref().unwrap().len());
+        commands.iter().for_each(check_slash_command);
+    }
+}
@@ -1,6 +1,8 @@
 use std::collections::VecDeque;
-use std::io::BufRead;
-use std::mem::take;
+
+use serenity::all::MessageId;
+
+use crate::source::Line;
 
 #[derive(Clone, Debug)]
 #[allow(dead_code)]
@@ -13,6 +15,11 @@ pub(crate) enum Role {
 pub(crate) struct Part {
     pub(crate) role: String,
     pub(crate) text: String,
+    /// Discord messages this part came from, if any; a response can be sent as several
+    pub(crate) message_ids: Vec<MessageId>,
+    /// Whether the part is from a prompt file rather than from a user or the model, so can
+    /// have variables to expand
+    pub(crate) template: bool,
 }
 
 #[derive(Clone, Debug, Default)]
@@ -20,6 +27,8 @@ pub(crate) struct Dialogue {
     pub(crate) parts: VecDeque<Part>,
     pub(crate) total_len: u64,
     pub(crate) max_len: u64,
+    /// Number of parts left out so far for not fitting in `max_len`
+    pub(crate) num_dropped: usize,
 }
 
 pub(crate) const MAXIMUM_DIALOGUE_LEN: u64 = 1_000;
@@ -30,14 +39,34 @@ impl Dialogue {
             parts: VecDeque::new(),
             total_len: 0,
             max_len: MAXIMUM_DIALOGUE_LEN,
+            num_dropped: 0,
         }
     }
 
     pub(crate) fn push(&mut self, role: &str, text: &str) {
-        let part = Part {
+        self.push_part(Part {
             role: role.to_string(),
             text: text.to_string(),
-        };
+            message_ids: Vec::new(),
+            template: false,
+        });
+    }
+
+    pub(crate) fn push_message(&mut self, role: &str, text: &str, message_id: MessageId) {
+        self.push_messages(role, text, &[message_id]);
+    }
+
+    /// Push a part that was sent as the given messages
+    pub(crate) fn push_messages(&mut self, role: &str, text: &str, message_ids: &[MessageId]) {
+        self.push_part(Part {
+            role: role.to_string(),
+            text: text.to_string(),
+            message_ids: message_ids.to_vec(),
+            template: false,
+        });
+    }
+
+    fn push_part(&mut self, part: Part) {
         self.total_len += part.len();
         self.parts.push_back(part);
         self.truncate_to_size();
@@ -45,16 +74,98 @@ impl Dialogue {
 
     pub(crate) fn append(&mut self, other: &Dialogue) {
         for part in &other.parts {
-            self.push(&part.role, &part.text);
+            self.push_part(part.clone());
+        }
+    }
+
+    /// Replace the text of the part from the given message; returns whether it was found
+    pub(crate) fn edit_message(&mut self, message_id: MessageId, text: &str) -> bool {
+        let Some(part) = self.parts.iter_mut().find(|p| p.message_ids.contains(&message_id)) else {
+            return false;
+        };
+        part.text = text.to_string();
+        self.recompute_len();
+        true
+    }
+
+    /// Remove the part from the given message; returns whether it was found
+    pub(crate) fn remove_message(&mut self, message_id: MessageId) -> bool {
+        let len_before = self.parts.len();
+        self.parts.retain(|p| !p.message_ids.contains(&message_id));
+        if self.parts.len() == len_before {
+            return false;
+        }
+        self.recompute_len();
+        true
+    }
+
+    /// Remove the parts from any of the given messages; returns the number of parts removed
+    pub(crate) fn remove_messages(&mut self, message_ids: &[MessageId]) -> usize {
+        let len_before = self.parts.len();
+        self.parts.retain(|p| !p.message_ids.iter().any(|id| message_ids.contains(id)));
+        self.recompute_len();
+        len_before - self.parts.len()
+    }
+
+    /// Remove the last `count` exchanges, each being a run of user parts and the model parts
+    /// that answered them; returns the number of parts removed
+    pub(crate) fn undo(&mut self, count: usize) -> usize {
+        let len_before = self.parts.len();
+        for _ in 0..count {
+            while self.parts.back().is_some_and(|p| p.role != "user") {
+                self.parts.pop_back();
+            }
+            while self.parts.back().is_some_and(|p| p.role == "user") {
+                self.parts.pop_back();
+            }
         }
+        self.recompute_len();
+        len_before - self.parts.len()
+    }
+
+    /// Remove all parts after the one from the given message; returns the number of parts
+    /// removed, or `None` if the message isn't in the dialogue
+    pub(crate) fn rewind_to(&mut self, message_id: MessageId) -> Option<usize> {
+        let pos = self.parts.iter().position(|p| p.message_ids.contains(&message_id))?;
+        let removed = self.parts.len() - pos - 1;
+        self.parts.truncate(pos + 1);
+        self.recompute_len();
+        Some(removed)
+    }
+
+    /// Replace the text of the last model part; returns whether there was one
+    pub(crate) fn correct_last(&mut self, text: &str) -> bool {
+        let Some(part) = self.parts.iter_mut().rev().find(|p| p.role == "model") else {
+            return false;
+        };
+        part.text = text.to_string();
+        self.recompute_len();
+        true
+    }
+
+    pub(crate) fn contains_message(&self, message_id: MessageId) -> bool {
+        self.parts.iter().any(|p| p.message_ids.contains(&message_id))
+    }
+
+    pub(crate) fn set_max_len(&mut self, max_len: u64) {
+        self.max_len = max_len;
+        self.truncate_to_size();
     }
 
+    fn recompute_len(&mut self) {
+        self.total_len = self.parts.iter().map(Part::len).sum();
+        self.truncate_to_size();
+    }
+
+    /// Leave out the oldest parts until the dialogue fits, apart from the newest part, which is
+    /// what's being answered
     fn truncate_to_size(&mut self) {
-        while self.total_len > self.max_len {
+        while self.total_len > self.max_len && self.parts.len() > 1 {
             let Some(part) = self.parts.pop_front() else {
                 break;
             };
             self.total_len -= part.len();
+            self.num_dropped += 1;
         }
     }
 
@@ -65,123 +176,216 @@ impl Dialogue {
 }
 
 impl Part {
-    fn len(&self) -> u64 {
+    pub(crate) fn len(&self) -> u64 {
         let text = self.text.trim();
         let words = text.split(' ').collect::<Vec<_>>();
         words.len() as u64
     }
 }
 
-pub(crate) fn split_result(result: &str, max_size: usize) -> Vec<String> {
-    /* Specific lines of the input are identified as split points:
-        - Blank lines (outside of code blocks, and not following headings).
-        - Code block lines starting ```; included with following group or current group
-          depending on parity.
-    */
-
-    #[derive(Default)]
-    struct Grouper {
-        groups: Vec<String>,
-        current_group: String,
+/// Quote another message's text, attributed to its author, ahead of a reply to it
+pub(crate) fn quote_reply(author: &str, quoted: &str, reply: &str) -> String {
+    let mut text = format!("(Replying to {author}:)\n");
+    for line in quoted.lines() {
+        text.push_str("> ");
+        text.push_str(line);
+        text.push('\n');
     }
+    text.push('\n');
+    text.push_str(reply);
+    text
+}
 
-    impl Grouper {
-        fn push(&mut self, line: &str) {
-            self.current_group.push_str(line);
-            self.current_group.push('\n');
-        }
+/// Roles that turns in a prompt file can have
+pub(crate) const ROLES: &[&str] = &["user", "model", "system"];
+
+/// Characters that are escaped by a `\` at the start of a prompt file line, so that the line isn't
+/// taken as a comment, user turn, section end, role marker, include or escape
+const ESCAPED: &[char] = &['#', '>', '-', '[', '!', '\\'];
+
+/// A turn being read from a prompt file
+struct Turn {
+    role: &'static str,
+    text: String,
+    /// Whether the turn was started by a role marker, so it runs until the next turn; otherwise
+    /// it's a single paragraph
+    explicit: bool,
+    /// Line the turn started on
+    start: Line,
+}
 
-        fn end_group(&mut self) {
-            let group = take(&mut self.current_group);
-            if !group.trim().is_empty() {
-                self.groups.push(group);
+impl Turn {
+    fn finish(mut self, dialogue: &mut Dialogue) -> Result<(), std::io::Error> {
+        if self.explicit {
+            if self.text.trim().is_empty() {
+                return Err(self.start.error(format!("[{}] turn has no text", self.role)));
             }
+            self.text.truncate(self.text.trim_end().len());
+            self.text.push('\n');
         }
+        dialogue.push(self.role, &self.text);
+        Ok(())
     }
+}
 
-    let mut grouper = Grouper::default();
-    let mut after_heading = false;
-    let mut in_code_block = false;
-
-    for line in result.lines() {
-        if line.is_empty() && !after_heading && !in_code_block {
-            grouper.push(line);
-            grouper.end_group();
-        } else if line.starts_with("```") {
-            in_code_block = !in_code_block;
-            if in_code_block {
-                grouper.end_group();
-            }
-            grouper.push(line);
-            if !in_code_block {
-                grouper.end_group();
-            }
-        } else {
-            after_heading = line.starts_with('*') && line.ends_with('*');
-            grouper.push(line);
-        }
+/// The role named by a role marker line such as `[user]`, or the unknown name in one
+fn role_marker(text: &str) -> Option<Result<&'static str, &str>> {
+    let name = text.trim().strip_prefix('[')?.strip_suffix(']')?;
+    if name.is_empty() || !name.chars().all(|c| c.is_ascii_lowercase()) {
+        return None;
     }
-    grouper.end_group();
+    Some(ROLES.iter().find(|r| **r == name).copied().ok_or(name))
+}
 
-    grouper.groups
+fn is_fence(text: &str) -> bool {
+    let text = text.trim_start();
+    text.starts_with("```") || text.starts_with("~~~")
 }
 
-pub(crate) fn merge_groups(groups: Vec<String>, max_size: usize) -> Vec<String> {
-    let mut segments = Vec::new();
-    let mut merged_group = String::new();
-    for group in groups {
-        if merged_group.len() + group.len() > max_size {
-            if !merged_group.is_empty() {
-                segments.push(take(&mut merged_group));
-            }
-            if group.len() > max_size {
-                merged_group.push_str("(Group too big to send!)");
-                continue;
-            }
-        }
-        merged_group.push_str(&group);
-    }
-    if !merged_group.is_empty() {
-        segments.push(merged_group);
+fn unescape(text: &str) -> &str {
+    match text.strip_prefix('\\') {
+        Some(rest) if rest.starts_with(ESCAPED) => rest,
+        _ => text,
     }
+}
 
-    segments
+/// Escape lines that would otherwise be read as markup, except in code blocks
+fn escape(text: &str) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut in_code_block = false;
+    for line in text.lines() {
+        if !in_code_block && line.starts_with(ESCAPED) {
+            result.push('\\');
+        }
+        if is_fence(line) {
+            in_code_block = !in_code_block;
+        }
+        result.push_str(line);
+        result.push('\n');
+    }
+    result.truncate(result.trim_end().len());
+    result
 }
 
-pub(crate) fn read_dialogue(f: &mut impl BufRead) -> Result<Dialogue, std::io::Error> {
+/// Read one section of a prompt file, up to a `---` line or the end.
+///
+/// A turn is either a paragraph, which is from the user if it starts with `>` and otherwise from
+/// the model, or starts with a role marker line like `[system]` and runs until the next turn.
+/// Lines starting with `#` are comments, a `\` at the start of a line makes the next character
+/// literal, and code blocks are read as they are.
+pub(crate) fn read_dialogue(lines: &mut impl Iterator<Item = Line>) -> Result<Dialogue, std::io::Error> {
     let mut dialogue = Dialogue::new();
+    let mut turn: Option<Turn> = None;
+    /* Opening line of the code block being read, if any */
+    let mut code_block: Option<Line> = None;
+
+    for line in lines {
+        if let (Some(_), Some(current)) = (&code_block, &mut turn) {
+            current.text.push_str(&line.text);
+            current.text.push('\n');
+            if is_fence(&line.text) {
+                code_block = None;
+            }
+            continue;
+        }
 
-    let mut dialogue_text = String::new();
-    loop {
-        let mut buf = String::new();
-        let num_read = f.read_line(&mut buf)?;
-
-        if num_read == 0 {
-            break;
+        let text = line.text.as_str();
+        if text.starts_with('#') {
+            continue;
         }
-        if buf.starts_with("---") {
+        if text.starts_with("---") {
             break;
         }
+        if let Some(role) = role_marker(text) {
+            let role = role.map_err(|name| line.error(format!("unknown role '{name}'; expected one of {}, or write '\\[{name}]' for the text itself", ROLES.join(", "))))?;
+            if let Some(previous) = turn.take() {
+                previous.finish(&mut dialogue)?;
+            }
+            turn = Some(Turn { role, text: String::new(), explicit: true, start: line });
+            continue;
+        }
+        if text.trim().is_empty() {
+            if let Some(mut current) = turn.take() {
+                current.text.push('\n');
+                if current.explicit {
+                    turn = Some(current);
+                } else {
+                    current.finish(&mut dialogue)?;
+                }
+            }
+            continue;
+        }
 
-        dialogue_text.push_str(&buf);
-    }
-
-    for text in split_result(&dialogue_text, 10000) {
-        let (role, text) = if text.starts_with('>') {
-            ("user", &text[2..])
-        } else {
-            ("model", text.as_str())
+        let content = match text.strip_prefix('>') {
+            Some(rest) => {
+                let continues_user = turn.as_ref().is_some_and(|t| !t.explicit && t.role == "user");
+                if !continues_user {
+                    if let Some(previous) = turn.take() {
+                        previous.finish(&mut dialogue)?;
+                    }
+                    turn = Some(Turn { role: "user", text: String::new(), explicit: false, start: line.clone() });
+                }
+                unescape(rest.strip_prefix(' ').unwrap_or(rest))
+            }
+            None => unescape(text),
         };
 
-        dialogue.push(role, &text);
+        let current = turn.get_or_insert_with(|| Turn { role: "model", text: String::new(), explicit: false, start: line.clone() });
+        current.text.push_str(content);
+        current.text.push('\n');
+        if is_fence(content) {
+            code_block = Some(line.clone());
+        }
+    }
+
+    if let Some(start) = code_block {
+        return Err(start.error("code block is never closed"));
+    }
+    if let Some(current) = turn {
+        current.finish(&mut dialogue)?;
     }
 
     Ok(dialogue)
 }
 
+/// Write the dialogue in the same format that `read_dialogue` reads
+pub(crate) fn write_dialogue(dialogue: &Dialogue) -> String {
+    let mut text = String::new();
+    /* Whether the last turn was started by a role marker, so would take in a model paragraph */
+    let mut explicit = false;
+    for part in &dialogue.parts {
+        /* A paragraph is easiest to read, but only user and model turns can be written as one,
+           and a turn with blank lines or a code block in it would be read back as several */
+        let part_text = part.text.trim();
+        let paragraph = (part.role == "user" || (part.role == "model" && !explicit))
+            && !part_text.lines().any(|line| line.trim().is_empty() || is_fence(line));
+        if !paragraph {
+            let escaped = escape(part_text);
+            if !escaped.is_empty() {
+                text.push_str(&format!("[{}]\n{escaped}\n\n", part.role));
+                explicit = true;
+            }
+            continue;
+        }
+        explicit = false;
+
+        if part.role == "user" {
+            text.push_str("> ");
+        }
+        text.push_str(&escape(part_text));
+        text.push_str("\n\n");
+    }
+    text
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
+    use crate::source::split_lines;
+
+    fn read(text: &str) -> Result<Dialogue, std::io::Error> {
+        read_dialogue(&mut split_lines(text, "test").into_iter())
+    }
 
     #[test]
     fn dialogue_len_and_truncation() {
@@ -190,6 +394,8 @@ mod test {
         let part = Part {
             role: "t".to_string(),
             text: big_str.clone(),
+            message_ids: Vec::new(),
+            template: false,
         };
         assert_eq!(400, part.len());
         d.push("t", &big_str.clone());
@@ -198,54 +404,86 @@ mod test {
         assert_eq!(800, d.total_len);
         d.push("t", &big_str.clone());
         assert_eq!(800, d.total_len);
+
+        /* The newest part is kept even if it doesn't fit */
+        d.set_max_len(100);
+        d.push("t", &big_str);
+        assert_eq!(1, d.parts.len());
+        assert_eq!(400, d.total_len);
     }
 
     #[test]
-    fn test_split_result() {
-        let input = "";
-        let segments = split_result(input, 0);
-        assert_eq!(0, segments.len());
-
-        let input = "one line\n\n";
-        let segments = split_result(input, 0);
-        assert_eq!(vec!["one line\n\n"], segments);
-
-        let input = "one\nsegment";
-        let segments = split_result(input, 0);
-        assert_eq!(vec!["one\nsegment\n"], segments);
-
-        let input = "now\ntwo\n\nsegments";
-        let segments = split_result(input, 0);
-        assert_eq!(vec!["now\ntwo\n\n", "segments\n"], segments);
-
-        let input = "line 1\n\n* heading *\n\nline 2";
-        let segments = split_result(input, 0);
-        assert_eq!(vec!["line 1\n\n", "* heading *\n\nline 2\n"], segments);
-
-        let input = "* bullet point\n\ntext";
-        let segments = split_result(input, 0);
-        assert_eq!(vec!["* bullet point\n\n", "text\n"], segments);
-
-        let input = "text\n```rust\nsome\n\ncode\n\nhere\n```\nmore text";
-        let segments = split_result(input, 0);
-        assert_eq!(
-            vec![
-                "text\n",
-                "```rust\nsome\n\ncode\n\nhere\n```\n",
-                "more text\n"
-            ],
-            segments
-        );
+    fn test_edit_and_remove_message() {
+        let mut d = Dialogue::new();
+        d.push_message("user", "one two three", MessageId::new(1));
+        d.push("model", "four");
+        d.push_message("user", "five six", MessageId::new(2));
+        assert_eq!(6, d.total_len);
+
+        assert!(d.edit_message(MessageId::new(1), "seven"));
+        assert_eq!("seven", d.parts[0].text);
+        assert_eq!(4, d.total_len);
+
+        assert!(d.remove_message(MessageId::new(2)));
+        assert_eq!(2, d.parts.len());
+        assert_eq!(2, d.total_len);
+
+        assert!(!d.edit_message(MessageId::new(3), "eight"));
+        assert!(!d.remove_message(MessageId::new(2)));
+
+        d.push_message("user", "nine", MessageId::new(3));
+        d.push_message("user", "ten", MessageId::new(4));
+        assert_eq!(2, d.remove_messages(&[MessageId::new(1), MessageId::new(4), MessageId::new(5)]));
+        assert_eq!(vec!["four", "nine"], d.parts.iter().map(|p| p.text.as_str()).collect::<Vec<_>>());
+        assert_eq!(2, d.total_len);
+
+        /* Any of the messages a response was sent as finds it */
+        d.push_messages("model", "eleven twelve", &[MessageId::new(6), MessageId::new(7)]);
+        assert!(d.contains_message(MessageId::new(7)));
+        assert!(d.edit_message(MessageId::new(7), "thirteen"));
+        assert_eq!("thirteen", d.parts[2].text);
+        assert!(d.remove_message(MessageId::new(7)));
+        assert!(!d.contains_message(MessageId::new(6)));
+    }
+
+    #[test]
+    fn test_undo() {
+        let mut d = Dialogue::new();
+        d.push("user", "a");
+        d.push("model", "b");
+        d.push("user", "c");
+        d.push("user", "d");
+        d.push("model", "e");
+
+        assert_eq!(3, d.undo(1));
+        assert_eq!(2, d.parts.len());
+        assert_eq!(2, d.total_len);
+
+        assert_eq!(2, d.undo(5));
+        assert_eq!(0, d.total_len);
     }
 
     #[test]
-    fn test_merge_groups() {
-        let groups = vec!["group1\n\n".into(), "group2".into()];
-        let segments = merge_groups(groups.clone(), 10);
-        assert_eq!(vec!["group1\n\n", "group2"], segments);
+    fn test_rewind_and_correct() {
+        let mut d = Dialogue::new();
+        d.push_message("user", "a", MessageId::new(1));
+        d.push_message("model", "b", MessageId::new(2));
+        d.push_message("user", "c", MessageId::new(3));
+        d.push_message("model", "d", MessageId::new(4));
+
+        assert_eq!(None, d.rewind_to(MessageId::new(5)));
+        assert_eq!(Some(2), d.rewind_to(MessageId::new(2)));
+        assert_eq!(2, d.parts.len());
+
+        assert!(d.correct_last("better answer"));
+        assert_eq!("better answer", d.parts[1].text);
+        assert_eq!(3, d.total_len);
+    }
 
-        let segments = merge_groups(groups, 20);
-        assert_eq!(vec!["group1\n\ngroup2"], segments);
+    #[test]
+    fn test_quote_reply() {
+        let text = quote_reply("Bob", "first\nsecond", "reply");
+        assert_eq!("(Replying to Bob:)\n> first\n> second\n\nreply", text);
     }
 
     #[test]
@@ -255,8 +493,7 @@ there\n
 Hello back
 to you";
 
-        let mut stream = TEST_DIALOGUE.as_bytes();
-        let dialogue = read_dialogue(&mut stream).unwrap();
+        let dialogue = read_dialogue(&mut split_lines(TEST_DIALOGUE, "test").into_iter()).unwrap();
 
         assert_eq!(2, dialogue.parts.len());
 
@@ -268,4 +505,98 @@ to you";
         assert_eq!("model", second_part.role);
         assert_eq!("Hello back\nto you\n", second_part.text);
     }
+
+    #[test]
+    fn test_write_dialogue() {
+        let mut dialogue = Dialogue::new();
+        dialogue.push("user", "Hello\nthere");
+        dialogue.push("model", "Hello back\n\nto you\n");
+
+        let text = write_dialogue(&dialogue);
+        assert_eq!("> Hello\nthere\n\n[model]\nHello back\n\nto you\n\n", text);
+
+        let dialogue = read_dialogue(&mut split_lines(&text, "test").into_iter()).unwrap();
+        let roles: Vec<_> = dialogue.parts.iter().map(|p| p.role.as_str()).collect();
+        assert_eq!(vec!["user", "model"], roles);
+        assert_eq!("Hello\nthere\n\n", dialogue.parts[0].text);
+    }
+
+    #[test]
+    fn test_read_dialogue_roles() {
+        const TEST_DIALOGUE: &str = "# A comment, which is ignored
+[system]
+You are a test.
+
+Still the system.
+[user]
+Hello
+> A user paragraph
+Continued
+
+A model paragraph
+---
+> Not read";
+
+        let mut lines = split_lines(TEST_DIALOGUE, "test").into_iter();
+        let dialogue = read_dialogue(&mut lines).unwrap();
+
+        let parts: Vec<_> = dialogue.parts.iter().map(|p| (p.role.as_str(), p.text.as_str())).collect();
+        assert_eq!(vec![
+            ("system", "You are a test.\n\nStill the system.\n"),
+            ("user", "Hello\n"),
+            ("user", "A user paragraph\nContinued\n\n"),
+            ("model", "A model paragraph\n"),
+        ], parts);
+        assert_eq!("> Not read", lines.next().unwrap().text);
+    }
+
+    #[test]
+    fn test_read_dialogue_escapes() {
+        let dialogue = read("\\# not a comment\n\\> not the user\n\\--- not the end\n\\[user]\n>\n> \\> quoted\n").unwrap();
+        assert_eq!("# not a comment\n> not the user\n--- not the end\n[user]\n", dialogue.parts[0].text);
+        assert_eq!("user", dialogue.parts[1].role);
+        assert_eq!("\n> quoted\n", dialogue.parts[1].text);
+
+        /* Markdown headings have to be escaped, or they're comments */
+        let dialogue = read("# Comment\n\\# Heading\n\\## Subheading\nText\n").unwrap();
+        assert_eq!("# Heading\n## Subheading\nText\n", dialogue.parts[0].text);
+
+        let dialogue = read("```\n# code\n\n---\n```\n").unwrap();
+        assert_eq!(1, dialogue.parts.len());
+        assert_eq!("```\n# code\n\n---\n```\n", dialogue.parts[0].text);
+    }
+
+    #[test]
+    fn test_read_dialogue_errors() {
+        let err = read("Hello\n[narrator]\nOnce upon a time\n").unwrap_err();
+        assert_eq!("test:2: unknown role 'narrator'; expected one of user, model, system, or write '\\[narrator]' for the text itself", err.to_string());
+
+        let err = read("Hello\n\n[user]\n\n[model]\nHi\n").unwrap_err();
+        assert_eq!("test:3: [user] turn has no text", err.to_string());
+
+        let err = read("Some code:\n```\nfn main() {}\n").unwrap_err();
+        assert_eq!("test:2: code block is never closed", err.to_string());
+    }
+
+    #[test]
+    fn test_write_dialogue_roundtrip() {
+        let mut dialogue = Dialogue::new();
+        dialogue.push("system", "Be helpful.\n\n# Rules\n- none\n");
+        dialogue.push("user", "(Replying to Bob:)\n> something\n\n[what]");
+        dialogue.push("model", "Here:\n```\n# code\n\n```\n");
+        dialogue.push("model", "> quoted\n\n>> not a user\n \n\\ slash");
+        dialogue.push("user", "First\n \nsecond\n\n> third");
+        dialogue.push("model", "[user]\n---\n!include x");
+
+        let text = write_dialogue(&dialogue);
+        let copy = read(&text).unwrap();
+
+        /* Only trailing spaces are lost */
+        let parts = |d: &Dialogue| -> Vec<(String, String)> {
+            d.parts.iter()
+                .map(|p| (p.role.clone(), p.text.trim().lines().map(str::trim_end).collect::<Vec<_>>().join("\n")))
+                .collect()
+        };
+        assert_eq!(parts(&dialogue), parts(&copy));
+    }
 }
@@ -1,13 +1,15 @@
 use std::sync::Arc;
 
 use serenity::gateway::ShardManager;
-use serenity::model::channel::Message;
+use serenity::model::channel::{Message, Reaction};
+use serenity::model::event::MessageUpdateEvent;
+use serenity::model::id::{ChannelId, GuildId, MessageId};
 use serenity::model::gateway::Ready;
 use serenity::prelude::*;
 use serenity::{async_trait, Error};
 use tracing::{error, info};
 
-use crate::bot::Bot;
+use crate::bot::{flush_feedback, save_feedback_periodically, watch_prompts, Bot, FEEDBACK_SAVE_INTERVAL};
 use crate::commands::create_framework;
 
 struct Handler;
@@ -48,21 +50,85 @@ impl EventHandler for Handler {
         }
     }
 
+    async fn message_update(&self, ctx: Context, _old: Option<Message>, _new: Option<Message>, event: MessageUpdateEvent) {
+        let Some(content) = &event.content else {
+            return;
+        };
+
+        let data = ctx.data.read().await;
+        let Some(bot) = data.get::<BotContainer>() else {
+            error!("Couldn't get bot object!");
+            return;
+        };
+
+        let bot = bot.lock().await;
+        bot.handle_edit(&ctx, &event, content).await;
+    }
+
+    async fn message_delete(&self, ctx: Context, channel_id: ChannelId, deleted_message_id: MessageId, _guild_id: Option<GuildId>) {
+        let data = ctx.data.read().await;
+        let Some(bot) = data.get::<BotContainer>() else {
+            error!("Couldn't get bot object!");
+            return;
+        };
+
+        let bot = bot.lock().await;
+        bot.handle_delete(channel_id, deleted_message_id).await;
+    }
+
+    async fn message_delete_bulk(&self, ctx: Context, channel_id: ChannelId, multiple_deleted_messages_ids: Vec<MessageId>, _guild_id: Option<GuildId>) {
+        let data = ctx.data.read().await;
+        let Some(bot) = data.get::<BotContainer>() else {
+            error!("Couldn't get bot object!");
+            return;
+        };
+
+        let bot = bot.lock().await;
+        for message_id in multiple_deleted_messages_ids {
+            bot.handle_delete(channel_id, message_id).await;
+        }
+    }
+
+    async fn reaction_add(&self, ctx: Context, reaction: Reaction) {
+        let data = ctx.data.read().await;
+        let Some(bot) = data.get::<BotContainer>() else {
+            error!("Couldn't get bot object!");
+            return;
+        };
+
+        let mut bot = bot.lock().await;
+        bot.handle_reaction(&ctx, &reaction, true);
+    }
+
+    async fn reaction_remove(&self, ctx: Context, reaction: Reaction) {
+        let data = ctx.data.read().await;
+        let Some(bot) = data.get::<BotContainer>() else {
+            error!("Couldn't get bot object!");
+            return;
+        };
+
+        let mut bot = bot.lock().await;
+        bot.handle_reaction(&ctx, &reaction, false);
+    }
+
     async fn ready(&self, _: Context, ready: Ready) {
         info!("{} is connected!", ready.user.name);
     }
 }
 
 pub(crate) async fn run_bot(bot: Bot, token: &str) -> Result<(), Error> {
+    let catalogue = bot.catalogue.clone();
+    let prompt_watch_interval = bot.prompt_watch_interval;
     let bot = Arc::new(Mutex::new(bot));
 
-    let framework = create_framework(bot.clone())?;
+    if let Some(interval) = prompt_watch_interval {
+        tokio::spawn(watch_prompts(bot.clone(), interval));
+    }
+    tokio::spawn(save_feedback_periodically(bot.clone(), FEEDBACK_SAVE_INTERVAL));
 
-    let intents = GatewayIntents::GUILD_MESSAGES
-        | GatewayIntents::DIRECT_MESSAGES
-        | GatewayIntents::MESSAGE_CONTENT;
+    let framework = create_framework(bot.clone(), &catalogue)?;
 
-    let mut client = Client::builder(token, intents)
+    let mut client = Client::builder(token, intents())
         .framework(framework)
         .event_handler(Handler)
         .await?;
@@ -70,11 +136,34 @@ pub(crate) async fn run_bot(bot: Bot, token: &str) -> Result<(), Error> {
     {
         let mut data = client.data.write().await;
         data.insert::<ShardManagerContainer>(Arc::clone(&client.shard_manager));
-        data.insert::<BotContainer>(bot);
+        data.insert::<BotContainer>(bot.clone());
     }
 
-    client.start().await?;
+    let result = client.start().await;
+    flush_feedback(&bot).await;
+    result?;
 
     Ok(())
 }
 
+/// The events the bot needs; guilds are needed to have their roles and channels in the cache
+fn intents() -> GatewayIntents {
+    GatewayIntents::GUILDS
+        | GatewayIntents::GUILD_MESSAGES
+        | GatewayIntents::DIRECT_MESSAGES
+        | GatewayIntents::GUILD_MESSAGE_REACTIONS
+        | GatewayIntents::DIRECT_MESSAGE_REACTIONS
+        | GatewayIntents::MESSAGE_CONTENT
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_intents() {
+        /* Without guilds in the cache, role mentions and permissions can't be checked */
+        assert!(intents().contains(GatewayIntents::GUILDS));
+        assert!(intents().contains(GatewayIntents::MESSAGE_CONTENT));
+    }
+}
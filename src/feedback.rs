@@ -0,0 +1,341 @@
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serenity::all::{GuildId, MessageId, ReactionType, UserId};
+
+/// File that feedback on the bot's responses is stored in
+pub(crate) const FEEDBACK_FILE: &str = "feedback.json";
+
+/// Responses kept so they can be voted on; older ones only count towards the totals
+const MAX_EXCHANGES: usize = 1000;
+
+/// What a user thought of a response, from their reaction to it
+#[derive(Clone, Copy, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Vote {
+    Up,
+    Down,
+}
+
+impl Vote {
+    /// The vote a reaction gives, if any; 👍 and 👎 count in any skin tone
+    pub(crate) fn from_reaction(emoji: &ReactionType) -> Option<Vote> {
+        let ReactionType::Unicode(emoji) = emoji else { return None };
+        if emoji.starts_with('👍') {
+            Some(Vote::Up)
+        } else if emoji.starts_with('👎') {
+            Some(Vote::Down)
+        } else {
+            None
+        }
+    }
+}
+
+/// A response the bot gave, with what produced it and the votes on it
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Exchange {
+    pub(crate) guild_id: Option<GuildId>,
+    /// The messages the response was sent in; a reaction to any of them counts
+    pub(crate) message_ids: Vec<MessageId>,
+    /// Filename of the prompt in use
+    pub(crate) prompt: String,
+    /// Hash of the dialogue the response was generated from, to tell exchanges apart
+    pub(crate) dialogue_hash: String,
+    pub(crate) backend: String,
+    pub(crate) model: String,
+    /// Each user's vote, as (user, vote) pairs
+    #[serde(default)]
+    pub(crate) votes: Vec<(UserId, Vote)>,
+}
+
+/// Approval of the responses made with one prompt and model
+#[derive(Clone, Debug, Default, PartialEq, Serialize, Deserialize)]
+pub(crate) struct Approval {
+    pub(crate) prompt: String,
+    pub(crate) backend: String,
+    pub(crate) model: String,
+    pub(crate) responses: usize,
+    pub(crate) up: usize,
+    pub(crate) down: usize,
+}
+
+impl Approval {
+    /// Fraction of votes that were up, if there were any
+    pub(crate) fn rate(&self) -> Option<f64> {
+        let total = self.up + self.down;
+        (total > 0).then(|| self.up as f64 / total as f64)
+    }
+
+    fn add(&mut self, exchange: &Exchange) {
+        self.responses += 1;
+        self.up += exchange.votes.iter().filter(|(_, v)| *v == Vote::Up).count();
+        self.down += exchange.votes.iter().filter(|(_, v)| *v == Vote::Down).count();
+    }
+
+    fn is_for(&self, exchange: &Exchange) -> bool {
+        (self.prompt.as_str(), self.backend.as_str(), self.model.as_str())
+            == (exchange.prompt.as_str(), exchange.backend.as_str(), exchange.model.as_str())
+    }
+}
+
+/// The bot's recent responses and what users thought of them, and totals for older ones
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Feedback {
+    /// Responses that can still be voted on, oldest first
+    exchanges: VecDeque<Exchange>,
+    /// Approval of the responses that have been dropped from the exchanges, by guild
+    #[serde(default)]
+    older: Vec<(Option<GuildId>, Approval)>,
+    /// Position of the exchange each response message is in, counting from the first exchange
+    /// recorded since loading
+    #[serde(skip)]
+    index: HashMap<MessageId, usize>,
+    /// Number of exchanges dropped since loading
+    #[serde(skip)]
+    num_dropped: usize,
+    /// Whether there are changes that haven't been saved
+    #[serde(skip)]
+    changed: bool,
+}
+
+impl Feedback {
+    pub(crate) fn record(&mut self, exchange: Exchange) {
+        let position = self.num_dropped + self.exchanges.len();
+        self.index.extend(exchange.message_ids.iter().map(|&id| (id, position)));
+        self.exchanges.push_back(exchange);
+        self.changed = true;
+
+        while self.exchanges.len() > MAX_EXCHANGES {
+            let Some(dropped) = self.exchanges.pop_front() else { break };
+            self.num_dropped += 1;
+            for id in &dropped.message_ids {
+                self.index.remove(id);
+            }
+
+            let i = match self.older.iter().position(|(g, a)| *g == dropped.guild_id && a.is_for(&dropped)) {
+                Some(i) => i,
+                None => {
+                    self.older.push((dropped.guild_id, Approval {
+                        prompt: dropped.prompt.clone(),
+                        backend: dropped.backend.clone(),
+                        model: dropped.model.clone(),
+                        ..Approval::default()
+                    }));
+                    self.older.len() - 1
+                }
+            };
+            self.older[i].1.add(&dropped);
+        }
+    }
+
+    /// Index the exchanges by message, after loading them
+    fn reindex(&mut self) {
+        self.num_dropped = 0;
+        self.index = self.exchanges.iter().enumerate()
+            .flat_map(|(i, e)| e.message_ids.iter().map(move |&id| (id, i)))
+            .collect();
+    }
+
+    /// Record a user's vote on a response; returns whether the message was one of the responses
+    pub(crate) fn vote(&mut self, message_id: MessageId, user_id: UserId, vote: Vote) -> bool {
+        let Some(exchange) = self.exchange_mut(message_id) else { return false };
+        exchange.votes.retain(|(u, _)| *u != user_id);
+        exchange.votes.push((user_id, vote));
+        self.changed = true;
+        true
+    }
+
+    /// Withdraw a user's vote on a response; returns whether they had voted that way
+    pub(crate) fn unvote(&mut self, message_id: MessageId, user_id: UserId, vote: Vote) -> bool {
+        let Some(exchange) = self.exchange_mut(message_id) else { return false };
+        let num_votes = exchange.votes.len();
+        exchange.votes.retain(|v| *v != (user_id, vote));
+        let changed = exchange.votes.len() != num_votes;
+        self.changed |= changed;
+        changed
+    }
+
+    /// The feedback as it's saved, if it has changed since this was last called; the changes are
+    /// taken to be saved, so `mark_changed` should be called if saving fails
+    pub(crate) fn take_unsaved(&mut self) -> Option<Result<String, serde_json::Error>> {
+        if !self.changed {
+            return None;
+        }
+        self.changed = false;
+        Some(serde_json::to_string(self))
+    }
+
+    pub(crate) fn mark_changed(&mut self) {
+        self.changed = true;
+    }
+
+    fn exchange_mut(&mut self, message_id: MessageId) -> Option<&mut Exchange> {
+        let position = self.index.get(&message_id)?;
+        self.exchanges.get_mut(position - self.num_dropped)
+    }
+
+    /// Approval of the responses in a guild, for each prompt and model
+    pub(crate) fn report(&self, guild_id: Option<GuildId>) -> Vec<Approval> {
+        let mut approvals: BTreeMap<(&str, &str, &str), Approval> = BTreeMap::new();
+        for (_, older) in self.older.iter().filter(|(g, _)| *g == guild_id) {
+            let key = (older.prompt.as_str(), older.backend.as_str(), older.model.as_str());
+            approvals.insert(key, older.clone());
+        }
+        for exchange in self.exchanges.iter().filter(|e| e.guild_id == guild_id) {
+            let key = (exchange.prompt.as_str(), exchange.backend.as_str(), exchange.model.as_str());
+            let approval = approvals.entry(key).or_insert_with(|| Approval {
+                prompt: exchange.prompt.clone(),
+                backend: exchange.backend.clone(),
+                model: exchange.model.clone(),
+                ..Approval::default()
+            });
+            approval.add(exchange);
+        }
+        approvals.into_values().collect()
+    }
+}
+
+/// A short hash of the dialogue sent to the backend; it's FNV-1a, so it's the same from one
+/// build to the next
+pub(crate) fn dialogue_hash(prompt: &[(String, String)]) -> String {
+    const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+    const FNV_PRIME: u64 = 0x100_0000_01b3;
+
+    let mut hash = FNV_OFFSET_BASIS;
+    for (role, text) in prompt {
+        /* Each string is ended with a zero byte, so moving text between them changes the hash */
+        for byte in role.bytes().chain([0]).chain(text.bytes()).chain([0]) {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    format!("{hash:016x}")
+}
+
+/// Load the feedback; if there's none yet, there's nothing to load
+pub(crate) fn load_feedback(path: impl AsRef<Path>) -> Result<Feedback, std::io::Error> {
+    let text = match std::fs::read_to_string(path) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Feedback::default()),
+        Err(err) => return Err(err),
+    };
+    let mut feedback: Feedback = serde_json::from_str(&text).map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))?;
+    feedback.reindex();
+    Ok(feedback)
+}
+
+/// Save the feedback, as given by `Feedback::take_unsaved`; it's written to another file first,
+/// so the bot stopping part way through can't leave a file that won't load
+pub(crate) fn write_feedback(path: impl AsRef<Path>, text: &str) -> Result<(), std::io::Error> {
+    let path = path.as_ref();
+    let temp_path = path.with_extension("json.tmp");
+    std::fs::write(&temp_path, text)?;
+    std::fs::rename(temp_path, path)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    fn exchange(message_id: u64, prompt: &str, model: &str) -> Exchange {
+        Exchange {
+            guild_id: Some(GuildId::new(1)),
+            message_ids: vec![MessageId::new(message_id), MessageId::new(message_id + 1)],
+            prompt: prompt.to_string(),
+            dialogue_hash: dialogue_hash(&[("user".to_string(), "Hello".to_string())]),
+            backend: "gemini".to_string(),
+            model: model.to_string(),
+            votes: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn test_vote_from_reaction() {
+        assert_eq!(Some(Vote::Up), Vote::from_reaction(&ReactionType::Unicode("👍".to_string())));
+        assert_eq!(Some(Vote::Up), Vote::from_reaction(&ReactionType::Unicode("👍🏽".to_string())));
+        assert_eq!(Some(Vote::Down), Vote::from_reaction(&ReactionType::Unicode("👎".to_string())));
+        assert_eq!(None, Vote::from_reaction(&ReactionType::Unicode("🎉".to_string())));
+    }
+
+    #[test]
+    fn test_report() {
+        let (alice, bob) = (UserId::new(100), UserId::new(101));
+        let mut feedback = Feedback::default();
+        feedback.record(exchange(10, "prompts/default.txt", "flash"));
+        feedback.record(exchange(20, "prompts/default.txt", "flash"));
+        feedback.record(exchange(30, "prompts/cluthor.txt", "flash"));
+
+        assert!(feedback.vote(MessageId::new(10), alice, Vote::Up));
+        assert!(feedback.vote(MessageId::new(11), bob, Vote::Up));
+        assert!(feedback.vote(MessageId::new(20), alice, Vote::Down));
+        assert!(feedback.vote(MessageId::new(20), alice, Vote::Up));
+        assert!(feedback.vote(MessageId::new(30), bob, Vote::Down));
+        assert!(!feedback.vote(MessageId::new(40), bob, Vote::Down));
+
+        assert!(!feedback.unvote(MessageId::new(30), bob, Vote::Up));
+        assert!(feedback.unvote(MessageId::new(11), bob, Vote::Up));
+
+        let report = feedback.report(Some(GuildId::new(1)));
+        assert_eq!(2, report.len());
+        assert_eq!(("prompts/cluthor.txt", 1, 0, 1), (report[0].prompt.as_str(), report[0].responses, report[0].up, report[0].down));
+        assert_eq!(("prompts/default.txt", 2, 2, 0), (report[1].prompt.as_str(), report[1].responses, report[1].up, report[1].down));
+        assert_eq!(Some(0.0), report[0].rate());
+        assert_eq!(Some(1.0), report[1].rate());
+
+        assert!(feedback.report(None).is_empty());
+    }
+
+    #[test]
+    fn test_old_exchanges() {
+        let alice = UserId::new(100);
+        let mut feedback = Feedback::default();
+        for i in 0..MAX_EXCHANGES as u64 + 10 {
+            feedback.record(exchange(10 * i + 10, "prompts/default.txt", "flash"));
+            feedback.vote(MessageId::new(10 * i + 10), alice, Vote::Up);
+        }
+        assert_eq!(MAX_EXCHANGES, feedback.exchanges.len());
+
+        /* Old responses still count, but can't be voted on */
+        assert!(!feedback.vote(MessageId::new(10), alice, Vote::Down));
+        assert!(feedback.vote(MessageId::new(200), alice, Vote::Down));
+        let report = feedback.report(Some(GuildId::new(1)));
+        assert_eq!(1, report.len());
+        assert_eq!((MAX_EXCHANGES + 10, MAX_EXCHANGES + 9, 1), (report[0].responses, report[0].up, report[0].down));
+    }
+
+    #[test]
+    fn test_dialogue_hash() {
+        /* The hash is saved, so it mustn't change */
+        assert_eq!("cbf29ce484222325", dialogue_hash(&[]));
+        let hash = dialogue_hash(&[("user".to_string(), "Hello".to_string())]);
+        assert_eq!(hash, dialogue_hash(&[("user".to_string(), "Hello".to_string())]));
+        assert_ne!(hash, dialogue_hash(&[("userH".to_string(), "ello".to_string())]));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let path = std::env::temp_dir().join(format!("clutha-feedback-test-{}.json", std::process::id()));
+        assert!(load_feedback(&path).unwrap().exchanges.is_empty());
+
+        let mut feedback = Feedback::default();
+        feedback.record(exchange(10, "prompts/default.txt", "flash"));
+        feedback.vote(MessageId::new(10), UserId::new(100), Vote::Down);
+        write_feedback(&path, &feedback.take_unsaved().unwrap().unwrap()).unwrap();
+
+        let mut loaded = load_feedback(&path).unwrap();
+        assert_eq!(1, loaded.exchanges.len());
+        assert_eq!(vec![(UserId::new(100), Vote::Down)], loaded.exchanges[0].votes);
+        assert_eq!(feedback.exchanges[0].dialogue_hash, loaded.exchanges[0].dialogue_hash);
+
+        /* Loaded responses can still be voted on, and are only saved again once they are */
+        assert!(loaded.take_unsaved().is_none());
+        assert!(loaded.vote(MessageId::new(11), UserId::new(101), Vote::Up));
+        write_feedback(&path, &loaded.take_unsaved().unwrap().unwrap()).unwrap();
+        assert!(loaded.take_unsaved().is_none());
+        assert_eq!(2, load_feedback(&path).unwrap().exchanges[0].votes.len());
+
+        std::fs::remove_file(path).unwrap();
+    }
+}
@@ -0,0 +1,139 @@
+//! Clutha, a Discord chat bot.  The `clutha` binary runs the bot, and `clutha-lint` checks prompt
+//! files before they're deployed.
+
+use std::path::Path;
+use std::process::ExitCode;
+use std::time::Duration;
+use tracing::error;
+use crate::action::ACTION_DIR;
+use crate::address::parse_name_triggers;
+use crate::backend::chatgpt::ChatGpt;
+use crate::bot::{Bot, MAX_BACKFILL_LEN};
+use crate::backend::gemini::Gemini;
+use crate::feedback::{load_feedback, FEEDBACK_FILE};
+use crate::prompt::{Catalogue, FRAGMENT_DIR, PROMPT_DIR};
+use crate::store::PromptStore;
+
+mod action;
+mod address;
+mod backend;
+mod bot;
+mod channel;
+mod commands;
+mod dialogue;
+mod discord;
+mod feedback;
+pub mod lint;
+mod markdown;
+mod markup;
+mod memory;
+mod overflow;
+mod permissions;
+mod prompt;
+mod source;
+mod store;
+mod template;
+
+/// Run the bot, with its configuration from the environment
+pub fn run() -> ExitCode {
+    tracing_subscriber::fmt::init();
+
+    let Ok(api_key) = std::env::var("GEMINI_API_KEY") else {
+        error!("GEMINI_API_KEY not set in environment");
+        return ExitCode::FAILURE;
+    };
+
+    let Ok(token) = std::env::var("DISCORD_TOKEN") else {
+        error!("DISCORD_TOKEN not set in environment");
+        return ExitCode::FAILURE;
+    };
+
+    // let Ok(api_key) = std::env::var("CHATGPT_API_KEY") else {
+    //     error!("CHATGPT_API_KEY not set in environment");
+    //     return ExitCode::FAILURE;
+    // };
+    // let chatgpt = ChatGpt::new(&api_key);
+
+    let backfill_len = match std::env::var("CLUTHA_BACKFILL") {
+        Ok(value) => match value.parse() {
+            Ok(len) => len,
+            Err(_) => {
+                error!("CLUTHA_BACKFILL should be a number of messages, up to {MAX_BACKFILL_LEN}");
+                return ExitCode::FAILURE;
+            }
+        },
+        Err(_) => 0,
+    };
+
+    let prompt_watch_interval = match std::env::var("CLUTHA_WATCH_PROMPTS") {
+        Ok(value) => match value.parse() {
+            Ok(secs) if secs > 0 => Some(Duration::from_secs(secs)),
+            _ => {
+                error!("CLUTHA_WATCH_PROMPTS should be a positive number of seconds");
+                return ExitCode::FAILURE;
+            }
+        },
+        Err(_) => None,
+    };
+
+    let name_triggers = std::env::var("CLUTHA_NAME_TRIGGERS")
+        .map(|value| parse_name_triggers(&value))
+        .unwrap_or_default();
+
+    /* Fragments are optional, so prompts directories without them still work */
+    let fragments = PromptStore::open(Path::new(PROMPT_DIR).join(FRAGMENT_DIR)).ok();
+    let action_store = PromptStore::open(Path::new(PROMPT_DIR).join(ACTION_DIR)).ok()
+        .map(|store| store.with_fragments(fragments.clone()));
+    let prompt_store = match PromptStore::open(PROMPT_DIR) {
+        Ok(store) => store.with_fragments(fragments),
+        Err(err) => {
+            error!("Couldn't open prompt directory {PROMPT_DIR}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+    let catalogue = match Catalogue::load(&prompt_store) {
+        Ok(catalogue) => catalogue,
+        Err(err) => {
+            error!("Couldn't read prompts from {PROMPT_DIR}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let feedback = match load_feedback(FEEDBACK_FILE) {
+        Ok(feedback) => feedback,
+        Err(err) => {
+            error!("Couldn't read feedback from {FEEDBACK_FILE}: {err}");
+            return ExitCode::FAILURE;
+        }
+    };
+
+    let gemini = Gemini::new(&api_key);
+    let backend = Box::new(gemini);
+    let bot = Bot {
+        backend,
+        channels: Default::default(),
+        guild_memories: Default::default(),
+        backfill_len,
+        prompt_store,
+        action_store,
+        catalogue,
+        prompt_watch_interval,
+        name_triggers,
+        feedback,
+    };
+
+    let rt = tokio::runtime::Builder::new_current_thread()
+        .enable_io()
+        .enable_time()
+        .build()
+        .unwrap();
+
+    rt.block_on(async {
+        let result = discord::run_bot(bot, &token).await;
+        if let Err(err) = result {
+            error!("Clutha bot finished with error: {err}");
+        }
+    });
+
+    ExitCode::SUCCESS
+}
@@ -0,0 +1,184 @@
+use std::fmt::{Display, Formatter};
+use std::path::Path;
+use std::process::ExitCode;
+
+use crate::dialogue::MAXIMUM_DIALOGUE_LEN;
+use crate::prompt::{load_prompt, Prompt, FRAGMENT_DIR, PROMPT_DIR};
+use crate::store::PromptStore;
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) enum Level {
+    Note,
+    Warning,
+    Error,
+}
+
+impl Display for Level {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Level::Note => write!(f, "note"),
+            Level::Warning => write!(f, "warning"),
+            Level::Error => write!(f, "error"),
+        }
+    }
+}
+
+/// Something worth telling a prompt's author about
+#[derive(Debug)]
+pub(crate) struct Finding {
+    pub(crate) level: Level,
+    pub(crate) message: String,
+}
+
+impl Finding {
+    fn new(level: Level, message: impl Into<String>) -> Finding {
+        Finding { level, message: message.into() }
+    }
+}
+
+/// Check a prompt that has been loaded successfully
+pub(crate) fn check_prompt(prompt: &Prompt) -> Vec<Finding> {
+    let mut findings = Vec::new();
+
+    /* Parts that don't fit are left out as the prompt is read, so only their number is known */
+    let prompt_len = prompt.prompt.total_len;
+    let total_len = prompt_len + prompt.initial.total_len;
+    if prompt.prompt.num_dropped > 0 {
+        findings.push(Finding::new(Level::Error, format!("prompt is too long, so the first {} of its paragraphs are left out (maximum is {MAXIMUM_DIALOGUE_LEN} words)", prompt.prompt.num_dropped)));
+    } else if prompt_len >= MAXIMUM_DIALOGUE_LEN {
+        findings.push(Finding::new(Level::Error, format!("prompt is {prompt_len} words, which leaves no room for dialogue (maximum is {MAXIMUM_DIALOGUE_LEN})")));
+    } else if prompt.initial.num_dropped > 0 || total_len > MAXIMUM_DIALOGUE_LEN {
+        findings.push(Finding::new(Level::Warning, format!("prompt and initial dialogue are {total_len} words, so the initial dialogue will be cut short (maximum is {MAXIMUM_DIALOGUE_LEN})")));
+    }
+
+    let unknown = prompt.unknown_variables();
+    if !unknown.is_empty() {
+        let names: Vec<_> = unknown.iter().map(|name| format!("{{{{{name}}}}}")).collect();
+        findings.push(Finding::new(Level::Warning, format!("unknown template variables: {}", names.join(", "))));
+    }
+
+    if prompt.initial.parts.is_empty() {
+        findings.push(Finding::new(Level::Warning, "no initial dialogue; it goes after a '---' line"));
+    } else if prompt.needs_response() {
+        findings.push(Finding::new(Level::Note, "initial dialogue ends with a user turn, so setting the prompt triggers a response"));
+    }
+
+    findings
+}
+
+/// Load a prompt file and check it; a prompt that can't be loaded gives a single error
+fn lint_file(path: &Path, fragments: Option<&PromptStore>) -> Vec<Finding> {
+    match load_prompt(path, fragments) {
+        Ok(prompt) => check_prompt(&prompt),
+        Err(err) => vec![Finding::new(Level::Error, err.to_string())],
+    }
+}
+
+/// Prompt files to check for a path given on the command line, which is either a prompt file or a
+/// directory of them
+fn prompt_files(path: &Path) -> Result<Vec<std::path::PathBuf>, std::io::Error> {
+    if !path.is_dir() {
+        return Ok(vec![path.to_path_buf()]);
+    }
+
+    let mut files: Vec<_> = std::fs::read_dir(path)?
+        .map(|entry| entry.map(|e| e.path()))
+        .collect::<Result<_, _>>()?;
+    files.retain(|f| f.is_file() && f.extension().is_some_and(|ext| ext == "txt"));
+    files.sort();
+    Ok(files)
+}
+
+/// Check the prompt files at the given paths, or in the prompt directory if there are none, and
+/// print what's found; fails if there are any errors
+pub fn run(paths: &[String]) -> ExitCode {
+    let default_paths = [PROMPT_DIR.to_string()];
+    let paths = if paths.is_empty() { &default_paths[..] } else { paths };
+
+    /* Includes are resolved from the same fragments as the bot uses, wherever the prompt is */
+    let fragments = PromptStore::open(Path::new(PROMPT_DIR).join(FRAGMENT_DIR)).ok();
+
+    let mut num_files = 0;
+    let mut num_errors = 0;
+    let mut num_warnings = 0;
+
+    for path in paths.iter().map(Path::new) {
+        let files = match prompt_files(path) {
+            Ok(files) => files,
+            Err(err) => {
+                println!("error: {}: {err}", path.display());
+                num_errors += 1;
+                continue;
+            }
+        };
+
+        for file in files {
+            num_files += 1;
+            for finding in lint_file(&file, fragments.as_ref()) {
+                match finding.level {
+                    Level::Error => num_errors += 1,
+                    Level::Warning => num_warnings += 1,
+                    Level::Note => (),
+                }
+                /* Load errors already say where they are */
+                if finding.level == Level::Error && finding.message.starts_with(&*file.to_string_lossy()) {
+                    println!("{}: {}", finding.level, finding.message);
+                } else {
+                    println!("{}: {}: {}", finding.level, file.display(), finding.message);
+                }
+            }
+        }
+    }
+
+    println!("Checked {num_files} prompt files: {num_errors} errors, {num_warnings} warnings");
+
+    if num_errors > 0 {
+        ExitCode::FAILURE
+    } else {
+        ExitCode::SUCCESS
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::prompt::parse_prompt;
+
+    fn levels(text: &str) -> Vec<(Level, String)> {
+        let prompt = parse_prompt(text, "test", None).unwrap();
+        check_prompt(&prompt).into_iter().map(|f| (f.level, f.message)).collect()
+    }
+
+    #[test]
+    fn test_check_prompt() {
+        assert!(levels("You are {{bot_name}}.\n---\nHello\n").is_empty());
+
+        let findings = levels("You are {{bot_name}} in {{place}}.\n");
+        assert_eq!(vec![
+            (Level::Warning, "unknown template variables: {{place}}".to_string()),
+            (Level::Warning, "no initial dialogue; it goes after a '---' line".to_string()),
+        ], findings);
+
+        let findings = levels("You are a test.\n---\n> Hello\n");
+        assert_eq!(Level::Note, findings[0].0);
+
+        let long_prompt = "word ".repeat(MAXIMUM_DIALOGUE_LEN as usize) + "\n---\nHello\n";
+        let findings = levels(&long_prompt);
+        assert_eq!(Level::Error, findings[0].0);
+        assert!(findings[0].1.contains("no room for dialogue"), "{}", findings[0].1);
+
+        let long_prompt = "word ".repeat(MAXIMUM_DIALOGUE_LEN as usize) + "\n\nmore words\n---\nHello\n";
+        let findings = levels(&long_prompt);
+        assert_eq!(Level::Error, findings[0].0);
+        assert!(findings[0].1.contains("first 1 of its paragraphs are left out"), "{}", findings[0].1);
+    }
+
+    #[test]
+    fn test_lint_prompt_dir() {
+        let fragments = PromptStore::open(Path::new(PROMPT_DIR).join(FRAGMENT_DIR)).ok();
+        for file in prompt_files(Path::new(PROMPT_DIR)).unwrap() {
+            let findings = lint_file(&file, fragments.as_ref());
+            assert!(findings.iter().all(|f| f.level < Level::Warning), "{}: {findings:?}", file.display());
+        }
+    }
+}
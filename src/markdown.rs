@@ -0,0 +1,743 @@
+use std::mem::take;
+
+/// Inline formatting that is closed at the end of a segment and reopened at the start of the next,
+/// if it's open there; longer markers first, so `**` isn't taken as two `*`s
+const INLINE_MARKERS: &[&str] = &["||", "**", "__", "~~", "`", "*", "_"];
+
+/// Room kept for closing and reopening formatting, and quoting, when a sentence is too long for a
+/// piece of its own and has to be split between words
+const FORMATTING_RESERVE: usize = 32;
+
+/// Discord quotes everything after a line starting with this, to the end of the message
+const REST_QUOTE: &str = ">>>";
+
+/// Put at the start of a line to stop it being taken as markdown syntax
+const ZERO_WIDTH_SPACE: char = '\u{200b}';
+
+/// Pieces of a code block smaller than this aren't worth having; the block is split as text
+const MIN_CODE_PIECE: usize = 16;
+
+/// The opening line of a fenced code block
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Fence<'a> {
+    /// The fence characters, e.g. "```" or "~~~~"
+    pub(crate) marker: &'a str,
+    /// The language named after the fence, if any
+    pub(crate) language: &'a str,
+}
+
+impl<'a> Fence<'a> {
+    /// The fence that a line opens, if it's a fence line
+    fn parse(line: &'a str) -> Option<Fence<'a>> {
+        let trimmed = line.trim_start_matches(' ');
+        if line.len() - trimmed.len() > 3 {
+            return None;
+        }
+        let c = trimmed.chars().next().filter(|&c| c == '`' || c == '~')?;
+        let len = trimmed.len() - trimmed.trim_start_matches(c).len();
+        let info = trimmed[len..].trim();
+        /* "```code```" on one line is inline code, not a fence */
+        if len < 3 || (c == '`' && info.contains('`')) {
+            return None;
+        }
+        Some(Fence {
+            marker: &trimmed[..len],
+            language: info.split_whitespace().next().unwrap_or(""),
+        })
+    }
+
+    /// Whether a line closes this fence
+    fn is_closed_by(&self, line: &str) -> bool {
+        let line = line.trim();
+        let c = self.marker.chars().next().unwrap_or('`');
+        line.len() >= self.marker.len() && line.chars().all(|ch| ch == c)
+    }
+}
+
+/// A part of some markdown that is kept in one segment if it fits: a code block, or a paragraph,
+/// list or quote along with any heading before it; blank lines go with the block before them
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub(crate) struct Block<'a> {
+    pub(crate) text: &'a str,
+    /// The opening fence, if it's a code block
+    pub(crate) fence: Option<Fence<'a>>,
+}
+
+impl<'a> Block<'a> {
+    /// The code in a code block, without its fences
+    pub(crate) fn code(&self) -> Option<&'a str> {
+        let fence = self.fence?;
+        let body = self.text.split_once('\n').map_or("", |(_, body)| body);
+        let mut end = 0;
+        for line in body.split_inclusive('\n') {
+            if fence.is_closed_by(line) {
+                break;
+            }
+            end += line.len();
+        }
+        Some(&body[..end])
+    }
+}
+
+/// Whether a line is a heading, which stays with the paragraph after it; `*text*` lines are taken
+/// as headings too, as models often use them that way
+fn is_heading(line: &str) -> bool {
+    let line = line.trim_end();
+    is_markdown_heading(line) || (line.len() > 1 && line.starts_with('*') && line.ends_with('*'))
+}
+
+/// Whether a line is shown as a heading
+fn is_markdown_heading(line: &str) -> bool {
+    let hashes = line.len() - line.trim_start_matches('#').len();
+    ((1..=3).contains(&hashes) && line[hashes..].starts_with(' ')) || line.starts_with("-# ")
+}
+
+/// Split markdown into blocks, which together are exactly the original text
+pub(crate) fn parse_blocks(text: &str) -> Vec<Block<'_>> {
+    let mut blocks = Vec::new();
+    let mut start = 0;
+    let mut fence = None;
+    let mut in_code = false;
+    let mut seen_content = false;
+    let mut after_blank = false;
+    let mut after_heading = false;
+
+    let mut pos = 0;
+    for line in text.split_inclusive('\n') {
+        let line_start = pos;
+        pos += line.len();
+
+        if in_code {
+            in_code = !fence.is_some_and(|f: Fence| f.is_closed_by(line));
+            continue;
+        }
+        if line.trim().is_empty() {
+            after_blank = true;
+            continue;
+        }
+
+        /* Blocks only start at lines with something on them, so blank lines end up with the
+           block before */
+        let new_fence = Fence::parse(line);
+        let heading = is_heading(line);
+        let boundary = new_fence.is_some() || fence.is_some() || heading || (after_blank && !after_heading);
+        if seen_content && boundary {
+            blocks.push(Block { text: &text[start..line_start], fence });
+            start = line_start;
+        }
+        if !seen_content || boundary {
+            fence = new_fence;
+        }
+
+        in_code = new_fence.is_some();
+        seen_content = true;
+        after_blank = false;
+        after_heading = heading;
+    }
+    if start < text.len() {
+        blocks.push(Block { text: &text[start..], fence });
+    }
+
+    blocks
+}
+
+/// Split markdown into segments of at most `max_size` bytes, keeping blocks together where they
+/// fit; code blocks that are split are closed and reopened, and so is formatting in split
+/// paragraphs
+pub(crate) fn split_markdown(text: &str, max_size: usize) -> Vec<String> {
+    /* Leave room to reopen a quote that runs to the end of the message */
+    let quotes_rest = starts_rest_quote(text);
+    let size = if quotes_rest { max_size.saturating_sub(REST_QUOTE.len() + 1) } else { max_size };
+
+    let mut segments = Vec::new();
+    let mut segment = String::new();
+    for block in parse_blocks(text) {
+        if segment.len() + block.text.len() <= size {
+            segment.push_str(block.text);
+            continue;
+        }
+        if !segment.is_empty() {
+            segments.push(take(&mut segment));
+        }
+
+        /* The last piece can share a segment with the blocks after it */
+        let mut pieces = split_block(&block, size);
+        segment = pieces.pop().unwrap_or_default();
+        segments.extend(pieces);
+    }
+    if !segment.is_empty() {
+        segments.push(segment);
+    }
+
+    if quotes_rest {
+        let mut quoting = false;
+        for segment in &mut segments {
+            let starts_quote = starts_rest_quote(segment);
+            if quoting {
+                segment.insert_str(0, &format!("{REST_QUOTE} "));
+            }
+            quoting |= starts_quote;
+        }
+    }
+
+    /* Discord won't send a message with nothing but whitespace */
+    let mut result: Vec<String> = Vec::new();
+    for segment in segments {
+        if !segment.trim().is_empty() {
+            result.push(segment);
+        } else if let Some(last) = result.last_mut().filter(|last| last.len() + segment.len() <= max_size) {
+            last.push_str(&segment);
+        }
+    }
+    result
+}
+
+/// Whether some markdown has a line outside code blocks that quotes the rest of the message
+fn starts_rest_quote(text: &str) -> bool {
+    parse_blocks(text).iter()
+        .filter(|b| b.fence.is_none())
+        .flat_map(|b| b.text.lines())
+        .any(|line| line.strip_prefix(REST_QUOTE).is_some_and(|rest| rest.is_empty() || rest.starts_with(' ')))
+}
+
+/// Split a block into pieces of at most `max_size` bytes
+pub(crate) fn split_block(block: &Block, max_size: usize) -> Vec<String> {
+    if block.text.len() <= max_size {
+        return vec![block.text.to_string()];
+    }
+    match block.fence {
+        Some(fence) => split_code(block.text, fence, max_size),
+        None => split_text(block.text, max_size),
+    }
+}
+
+/// Split a code block between lines, closing it at the end of each piece and reopening it, with
+/// the same language, at the start of the next
+fn split_code(text: &str, fence: Fence, max_size: usize) -> Vec<String> {
+    let Some((open_line, body)) = text.split_inclusive('\n').next().map(|l| (l, &text[l.len()..])) else {
+        return Vec::new();
+    };
+    let lines: Vec<&str> = body.split_inclusive('\n').collect();
+    let (code_lines, close_line, trailing) = match lines.iter().position(|l| fence.is_closed_by(l)) {
+        Some(i) => (&lines[..i], Some(lines[i].trim_end()), lines[i + 1..].concat()),
+        None => (&lines[..], None, String::new()),
+    };
+
+    let close_len = close_line.map_or(0, str::len).max(fence.marker.len()) + 2;
+    let budget = max_size.saturating_sub(open_line.len() + close_len);
+    if budget < MIN_CODE_PIECE || !open_line.ends_with('\n') {
+        return split_text(text, max_size);
+    }
+
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    for line in code_lines {
+        for chunk in chunks(line, budget) {
+            if piece.len() + chunk.len() > budget && !piece.is_empty() {
+                pieces.push(take(&mut piece));
+            }
+            piece.push_str(chunk);
+        }
+    }
+    pieces.push(piece);
+
+    let num_pieces = pieces.len();
+    pieces.into_iter().enumerate()
+        .map(|(i, piece)| {
+            let mut text = format!("{open_line}{piece}");
+            if !text.ends_with('\n') {
+                text.push('\n');
+            }
+            match close_line {
+                Some(close_line) if i + 1 == num_pieces => {
+                    text.push_str(close_line);
+                    text.push('\n');
+                    /* Blank lines after the block are only kept if there's room */
+                    if text.len() + trailing.len() <= max_size {
+                        text.push_str(&trailing);
+                    }
+                }
+                _ => {
+                    text.push_str(fence.marker);
+                    text.push('\n');
+                }
+            }
+            text
+        })
+        .collect()
+}
+
+/// Split text into chunks of at most `size` bytes, between characters
+fn chunks(text: &str, size: usize) -> Vec<&str> {
+    let mut chunks = Vec::new();
+    let mut rest = text;
+    while rest.len() > size {
+        let mut end = size;
+        while !rest.is_char_boundary(end) {
+            end -= 1;
+        }
+        chunks.push(&rest[..end]);
+        rest = &rest[end..];
+    }
+    chunks.push(rest);
+    chunks
+}
+
+/// Split a paragraph, list or quote between lines, or between the sentences of lines that are too
+/// long; formatting open at the end of a piece is closed and reopened in the next
+fn split_text(text: &str, max_size: usize) -> Vec<String> {
+    /* Formatting is only reopened if it's closed later, so where each marker is last seen */
+    let last_markers: Vec<(&str, usize)> = INLINE_MARKERS.iter()
+        .filter_map(|&m| Some((m, text.rfind(m)?)))
+        .collect();
+    let last_markers = &last_markers;
+    let closed_after = |pos: usize| move |marker: &&str| last_markers.iter().any(|&(m, i)| m == *marker && i >= pos);
+
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    /* Formatting open at the start of the piece, which is reopened there */
+    let mut reopened = Vec::new();
+    let mut pos = 0;
+    for line in text.split_inclusive('\n') {
+        let line_end = pos + line.len();
+        if fits(&reopened, &format!("{piece}{line}"), closed_after(line_end), max_size) {
+            piece.push_str(line);
+            pos = line_end;
+            continue;
+        }
+        if !piece.is_empty() {
+            end_piece(&mut pieces, &mut piece, &mut reopened, closed_after(pos));
+        }
+        if fits(&reopened, line, closed_after(line_end), max_size) {
+            piece.push_str(line);
+            pos = line_end;
+            continue;
+        }
+
+        /* The rest of a quoted line that's too long is quoted too */
+        let prefix = if line.starts_with('>') && !line.starts_with(REST_QUOTE) { "> " } else { "" };
+        let max_sentence = max_size.saturating_sub(FORMATTING_RESERVE + prefix.len()).max(MIN_CODE_PIECE);
+        let sentences = sentences(line).into_iter().flat_map(|s| split_sentences(s, max_sentence));
+        for (i, sentence) in sentences.enumerate() {
+            let end = pos + sentence.len();
+            if !piece.is_empty() && !fits(&reopened, &format!("{piece}{sentence}"), closed_after(end), max_size) {
+                end_piece(&mut pieces, &mut piece, &mut reopened, closed_after(pos));
+            }
+            if piece.is_empty() && i > 0 {
+                piece.push_str(prefix);
+            }
+            piece.push_str(&sentence);
+            pos = end;
+        }
+    }
+    if !piece.is_empty() {
+        pieces.push(piece);
+    }
+
+    balance_formatting(&pieces)
+}
+
+/// Whether a piece of a split paragraph fits in `max_size` bytes once formatting is reopened at
+/// its start and closed at its end, and it's kept from starting a code block or heading
+fn fits(reopened: &[&'static str], piece: &str, closed_later: impl Fn(&&str) -> bool, max_size: usize) -> bool {
+    let mut open = reopened.to_vec();
+    scan_formatting(piece, &mut open);
+    open.retain(closed_later);
+    let markers: usize = reopened.iter().chain(&open).map(|m| m.len()).sum();
+
+    let first_line = format!("{}{}", reopened.concat(), piece.lines().next().unwrap_or_default());
+    let guard = if Fence::parse(&first_line).is_some() || is_markdown_heading(&first_line) { ZERO_WIDTH_SPACE.len_utf8() } else { 0 };
+
+    piece.len() + markers + guard <= max_size
+}
+
+/// Add a piece to the pieces, and start the next one with the formatting left open by it
+fn end_piece(pieces: &mut Vec<String>, piece: &mut String, reopened: &mut Vec<&'static str>, closed_later: impl Fn(&&str) -> bool) {
+    scan_formatting(piece, reopened);
+    reopened.retain(closed_later);
+    pieces.push(take(piece));
+}
+
+/// Close inline formatting that's open at the end of each piece and reopen it at the start of
+/// the next, if it's closed later on; formatting that's never closed is just text
+fn balance_formatting(pieces: &[String]) -> Vec<String> {
+    let mut open: Vec<&str> = Vec::new();
+    let mut balanced = Vec::new();
+    for (i, piece) in pieces.iter().enumerate() {
+        let mut text = open.concat();
+        text.push_str(piece);
+
+        scan_formatting(piece, &mut open);
+        let rest = pieces[i + 1..].concat();
+        open.retain(|marker| rest.contains(marker));
+
+        let closers: String = open.iter().rev().copied().collect();
+        text.insert_str(text.trim_end().len(), &closers);
+
+        /* A piece that starts part way through a line, or with reopened formatting, mustn't start
+           a code block or heading that wasn't there */
+        let first_line = text.lines().next().unwrap_or_default();
+        if i > 0 && (Fence::parse(first_line).is_some() || is_markdown_heading(first_line) && !pieces[i - 1].ends_with('\n')) {
+            text.insert(0, ZERO_WIDTH_SPACE);
+        }
+        balanced.push(text);
+    }
+    balanced
+}
+
+/// Update the inline formatting that's open after some text
+fn scan_formatting(text: &str, open: &mut Vec<&'static str>) {
+    let mut rest = text;
+    let mut before = None;
+    while let Some(c) = rest.chars().next() {
+        if c == '\\' {
+            let escaped = rest[1..].chars().next();
+            rest = &rest[1 + escaped.map_or(0, char::len_utf8)..];
+            before = escaped;
+            continue;
+        }
+
+        /* Nothing is formatting inside inline code, apart from its end */
+        let in_code = open.last() == Some(&"`");
+        let marker = INLINE_MARKERS.iter()
+            .find(|&&m| {
+                rest.starts_with(m)
+                    && (!in_code || m == "`")
+                    && is_emphasis(m, before, rest[m.len()..].chars().next(), open.contains(&m))
+            })
+            .copied();
+        match marker {
+            Some(marker) => {
+                match open.iter().rposition(|&m| m == marker) {
+                    Some(i) => { open.remove(i); }
+                    None => open.push(marker),
+                }
+                rest = &rest[marker.len()..];
+                before = marker.chars().last();
+            }
+            None => {
+                rest = &rest[c.len_utf8()..];
+                before = Some(c);
+            }
+        }
+    }
+}
+
+/// Whether a single `*` or `_` between the given characters opens or closes emphasis, rather than
+/// being a bullet point, multiplication or part of a name like `snake_case`; other markers always do
+fn is_emphasis(marker: &str, before: Option<char>, after: Option<char>, is_open: bool) -> bool {
+    let is_space = |c: Option<char>| c.is_none_or(char::is_whitespace);
+    let is_word = |c: Option<char>| c.is_some_and(char::is_alphanumeric);
+    match marker {
+        "*" if is_open => !is_space(before),
+        "*" => !is_space(after),
+        "_" if is_open => !is_space(before) && !is_word(after),
+        "_" => !is_space(after) && !is_word(before),
+        _ => true,
+    }
+}
+
+/// Split text into pieces of at most `max_size` bytes, between sentences where possible, and
+/// otherwise between words
+pub(crate) fn split_sentences(text: &str, max_size: usize) -> Vec<String> {
+    let mut pieces = Vec::new();
+    let mut piece = String::new();
+    for sentence in sentences(text) {
+        if piece.len() + sentence.len() > max_size && !piece.is_empty() {
+            pieces.push(take(&mut piece));
+        }
+        if sentence.len() <= max_size {
+            piece.push_str(sentence);
+            continue;
+        }
+
+        /* A sentence that's too long on its own is split wherever it has to be */
+        let mut rest = sentence;
+        while rest.len() > max_size {
+            let mut end = max_size;
+            while !rest.is_char_boundary(end) {
+                end -= 1;
+            }
+            let end = rest[..end].rfind(' ').filter(|&i| i > 0).map_or(end, |i| i + 1);
+            pieces.push(rest[..end].to_string());
+            rest = &rest[end..];
+        }
+        piece.push_str(rest);
+    }
+    if !piece.is_empty() {
+        pieces.push(piece);
+    }
+    pieces
+}
+
+/// The sentences in some text, each with the spaces after it
+fn sentences(text: &str) -> Vec<&str> {
+    let mut sentences = Vec::new();
+    let mut start = 0;
+    let mut chars = text.char_indices().peekable();
+    while let Some((_, c)) = chars.next() {
+        let ends_sentence = matches!(c, '.' | '!' | '?' | '\n');
+        if !ends_sentence || chars.peek().is_some_and(|&(_, next)| !next.is_whitespace()) {
+            continue;
+        }
+        while chars.peek().is_some_and(|&(_, next)| next.is_whitespace()) {
+            chars.next();
+        }
+        let end = chars.peek().map_or(text.len(), |&(i, _)| i);
+        sentences.push(&text[start..end]);
+        start = end;
+    }
+    if start < text.len() {
+        sentences.push(&text[start..]);
+    }
+    sentences
+}
+
+#[cfg(test)]
+mod test {
+    use std::collections::HashSet;
+
+    use rand::rngs::StdRng;
+    use rand::{Rng, SeedableRng};
+
+    use super::*;
+
+    /// Discord's limit on the length of a message, in characters
+    const DISCORD_MAX_LEN: usize = 2000;
+
+    fn texts<'a>(blocks: &[Block<'a>]) -> Vec<&'a str> {
+        blocks.iter().map(|b| b.text).collect()
+    }
+
+    fn is_unclosed(block: &Block) -> bool {
+        block.fence.is_some_and(|fence| !block.text.lines().skip(1).any(|line| fence.is_closed_by(line)))
+    }
+
+    #[test]
+    fn test_parse_blocks() {
+        assert!(parse_blocks("").is_empty());
+        assert_eq!(vec!["one\nsegment"], texts(&parse_blocks("one\nsegment")));
+        assert_eq!(vec!["now\ntwo\n\n", "blocks"], texts(&parse_blocks("now\ntwo\n\nblocks")));
+        assert_eq!(vec!["\n\nleading blank lines\n"], texts(&parse_blocks("\n\nleading blank lines\n")));
+
+        /* Headings stay with what follows them */
+        let blocks = parse_blocks("line 1\n\n* heading *\n\nline 2\n# Title\n\ntext\n-# small print");
+        assert_eq!(vec!["line 1\n\n", "* heading *\n\nline 2\n", "# Title\n\ntext\n", "-# small print"], texts(&blocks));
+
+        let blocks = parse_blocks("* bullet point\n* another\n\ntext");
+        assert_eq!(vec!["* bullet point\n* another\n\n", "text"], texts(&blocks));
+
+        let blocks = parse_blocks("text\n```rust\nsome\n\ncode\n```\n\nmore text");
+        assert_eq!(vec!["text\n", "```rust\nsome\n\ncode\n```\n\n", "more text"], texts(&blocks));
+        assert_eq!(Some(Fence { marker: "```", language: "rust" }), blocks[1].fence);
+        assert_eq!(Some("some\n\ncode\n"), blocks[1].code());
+        assert_eq!(None, blocks[2].code());
+
+        /* Fences only close with the same character, and at least as many of them */
+        let blocks = parse_blocks("~~~\n```\n~~~\n````md\n```\n`````\nafter");
+        assert_eq!(vec!["~~~\n```\n~~~\n", "````md\n```\n`````\n", "after"], texts(&blocks));
+        assert_eq!(Some("```\n"), blocks[1].code());
+
+        assert_eq!(vec!["use ```inline``` code"], texts(&parse_blocks("use ```inline``` code")));
+        assert_eq!(Some("unclosed\n"), parse_blocks("```\nunclosed\n")[0].code());
+    }
+
+    #[test]
+    fn test_split_markdown() {
+        assert_eq!(vec!["short"], split_markdown("short", 100));
+
+        let text = "first paragraph\n\nsecond paragraph\n\nthird";
+        assert_eq!(vec!["first paragraph\n\n", "second paragraph\n\nthird"], split_markdown(text, 30));
+
+        /* Split code is closed and reopened in the same language */
+        let text = format!("~~~python\n{}~~~\n", "print(1)\n".repeat(10));
+        let segments = split_markdown(&text, 60);
+        assert_eq!(vec![format!("~~~python\n{}~~~\n", "print(1)\n".repeat(5)); 2], segments);
+
+        let text = format!("```rust\n{}", "let x = 1;\n".repeat(10));
+        let segments = split_markdown(&text, 80);
+        assert!(segments.iter().all(|s| s.starts_with("```rust\n") && s.ends_with("\n```\n")), "{segments:?}");
+
+        /* So is formatting in a split paragraph */
+        let text = format!("||{}||", "Secret. ".repeat(10));
+        let segments = split_markdown(&text, 60);
+        assert!(segments.iter().all(|s| s.starts_with("||") && s.trim_end().ends_with("||")), "{segments:?}");
+
+        let text = format!("_{}_", "Slanted. ".repeat(10));
+        let segments = split_markdown(&text, 60);
+        assert!(segments.iter().all(|s| s.starts_with('_') && s.trim_end().ends_with('_')), "{segments:?}");
+
+        /* Underscores in names and lone asterisks aren't formatting */
+        let text = "Use snake_case names. Then 2 * 3 is six. ".repeat(4);
+        assert_eq!(text, split_markdown(&text, 60).concat());
+
+        let text = format!("> {}", "Quoted. ".repeat(10));
+        let segments = split_markdown(&text, 60);
+        assert!(segments.iter().all(|s| s.starts_with("> ")), "{segments:?}");
+
+        let text = format!("Intro\n\n>>> {}", "Quoted. ".repeat(10));
+        let segments = split_markdown(&text, 60);
+        assert!(segments[1..].iter().all(|s| s.starts_with(">>> ")), "{segments:?}");
+    }
+
+    #[test]
+    fn test_sentences() {
+        assert_eq!(vec!["One. ", "Two? ", "Three!\n\n", "v1.2 is out"], sentences("One. Two? Three!\n\nv1.2 is out"));
+        assert!(sentences("").is_empty());
+    }
+
+    #[test]
+    fn test_split_sentences() {
+        let pieces = split_sentences("One two. Three four. Five six.", 21);
+        assert_eq!(vec!["One two. Three four. ", "Five six."], pieces);
+
+        let pieces = split_sentences("A long sentence without a break", 10);
+        assert_eq!("A long sentence without a break", pieces.concat());
+        assert!(pieces.iter().all(|p| p.len() <= 10), "{pieces:?}");
+    }
+
+    /// Some random markdown, made of things that are likely to be split badly
+    fn random_markdown(rng: &mut StdRng) -> String {
+        const WORDS: &[&str] = &[
+            "word ", "Sentence. ", "Why? ", "héllo ", "日本語 ", "🦀 ", "** ", "__ ", "~~ ", "|| ", "` ",
+            "**bold** ", "||spoiler|| ", "`code` ", "\\* ", "e.g. ", "*italic* ", "_italic_ ", "snake_case ",
+            "2 * 3 ",
+        ];
+        const LINE_STARTS: &[&str] = &["", "", "", "# ", "## ", "-# ", "- ", "* ", "1. ", "> ", ">>> ", "*Heading*"];
+        const FENCES: &[&str] = &["```", "```rust", "~~~", "````md", "~~~~ sh"];
+
+        let mut text = String::new();
+        for _ in 0..rng.gen_range(1..60) {
+            match rng.gen_range(0..10) {
+                0 => {
+                    let fence = FENCES[rng.gen_range(0..FENCES.len())];
+                    text.push_str(fence);
+                    text.push('\n');
+                    for _ in 0..rng.gen_range(0..80) {
+                        text.push_str(&"x".repeat(rng.gen_range(0..120)));
+                        text.push('\n');
+                    }
+                    /* Sometimes the response is cut off before the code is closed */
+                    if rng.gen_bool(0.9) {
+                        text.push_str(&fence[..fence.find(|c: char| c != '`' && c != '~').unwrap_or(fence.len())]);
+                        text.push('\n');
+                    }
+                }
+                1 => text.push_str(&"y".repeat(rng.gen_range(1..3000))),
+                _ => {
+                    text.push_str(LINE_STARTS[rng.gen_range(0..LINE_STARTS.len())]);
+                    for _ in 0..rng.gen_range(0..200) {
+                        text.push_str(WORDS[rng.gen_range(0..WORDS.len())]);
+                    }
+                }
+            }
+            text.push_str(["\n", "\n\n", "\n\n\n"][rng.gen_range(0..3)]);
+        }
+        text
+    }
+
+    /// Characters that formatting and code fences added to a segment are made of
+    const MARKUP: &[char] = &['|', '*', '_', '~', '`'];
+
+    /// Lengths of what may have been added to the start of a segment: a code block's opening
+    /// fence, or reopened formatting followed by a quote
+    fn added_prefix_lens(segment: &str) -> Vec<usize> {
+        let mut lens = Vec::new();
+        let markers = segment.len() - segment.trim_start_matches(MARKUP).len();
+        for len in 0..=markers {
+            lens.push(len);
+            if segment[len..].starts_with("> ") {
+                lens.push(len + 2);
+            }
+        }
+        if let Some(line) = segment.split_inclusive('\n').next().filter(|l| l.ends_with('\n') && Fence::parse(l).is_some()) {
+            lens.push(line.len());
+        }
+        lens
+    }
+
+    /// Lengths of what may have been added to the end of a segment: closers for formatting, or a
+    /// code block's closing fence
+    fn added_suffix_lens(segment: &str) -> Vec<usize> {
+        let mut lens = Vec::new();
+        let markers = segment.len() - segment.trim_end_matches(MARKUP).len();
+        for len in 0..=markers {
+            lens.push(len);
+            if segment[..segment.len() - len].ends_with('\n') {
+                lens.push(len + 1);
+            }
+        }
+        lens
+    }
+
+    /// Whether the segments are exactly the text, apart from whitespace between them and markup
+    /// added to keep each one well-formed: zero-width spaces, quotes, code fences and formatting
+    /// reopened at the start, and formatting and code closed at the end
+    fn nothing_lost(text: &str, segments: &[String]) -> bool {
+        let mut quoting = false;
+        let mut originals = Vec::new();
+        for segment in segments {
+            let segment = match quoting {
+                true => match segment.strip_prefix(">>> ") {
+                    Some(segment) => segment,
+                    None => return false,
+                },
+                false => segment,
+            };
+            quoting |= starts_rest_quote(segment);
+            originals.push(segment.replace(ZERO_WIDTH_SPACE, ""));
+        }
+        carries_on(text, &originals, &mut HashSet::new())
+    }
+
+    /// Whether the segments, without what was added to them, are the rest of the text; what was
+    /// added can be ambiguous, so each possibility is tried, and the ones that failed remembered
+    fn carries_on(rest: &str, segments: &[String], failed: &mut HashSet<(usize, usize)>) -> bool {
+        let rest = rest.trim_start();
+        let Some((segment, later)) = segments.split_first() else {
+            return rest.is_empty();
+        };
+        if failed.contains(&(rest.len(), later.len())) {
+            return false;
+        }
+
+        let body = segment.trim_end();
+        let mut lens: Vec<usize> = added_prefix_lens(body).into_iter()
+            .flat_map(|start| added_suffix_lens(&body[start..]).into_iter().map(move |end| (start, end)))
+            .map(|(start, end)| body[start..body.len() - end].trim_start())
+            .filter(|original| rest.starts_with(original))
+            .map(str::len)
+            .collect();
+        lens.sort_unstable_by(|a, b| b.cmp(a));
+        lens.dedup();
+
+        if lens.into_iter().any(|len| carries_on(&rest[len..], later, failed)) {
+            return true;
+        }
+        failed.insert((rest.len(), later.len()));
+        false
+    }
+
+    #[test]
+    fn test_split_markdown_properties() {
+        let mut rng = StdRng::seed_from_u64(50);
+        for case in 0..300 {
+            let text = random_markdown(&mut rng);
+            let max_size = if case % 2 == 0 { DISCORD_MAX_LEN } else { rng.gen_range(100..DISCORD_MAX_LEN) };
+            let segments = split_markdown(&text, max_size);
+
+            for segment in &segments {
+                assert!(segment.len() <= max_size, "case {case}: segment of {} bytes:\n{segment}", segment.len());
+                assert!(segment.chars().count() <= DISCORD_MAX_LEN);
+                assert!(!segment.trim().is_empty(), "case {case}: empty segment");
+            }
+            assert!(nothing_lost(&text, &segments), "case {case}: content lost from:\n{text}");
+
+            /* Code is only left open at the end of the response */
+            for segment in &segments[..segments.len().saturating_sub(1)] {
+                let segment = segment.strip_prefix(">>> ").unwrap_or(segment);
+                assert!(!parse_blocks(segment).iter().any(is_unclosed), "case {case}: code left open in:\n{segment}");
+            }
+        }
+    }
+}
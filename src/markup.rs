@@ -0,0 +1,158 @@
+use std::collections::HashMap;
+
+use serenity::all::{CreateAllowedMentions, UserId};
+
+/// A Discord markup token in message text
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Token {
+    /// `<@id>` or `<@!id>`
+    User(u64),
+    /// `<@&id>`
+    Role(u64),
+    /// `<#id>`
+    Channel(u64),
+    /// `<:name:id>` or `<a:name:id>`
+    Emoji(String),
+}
+
+/// Parse a token at the start of the text, returning it and its length
+fn parse_token(text: &str) -> Option<(Token, usize)> {
+    let end = text.find('>')?;
+    let inner = text.strip_prefix('<')?.get(..end - 1)?;
+
+    let token = if let Some(id) = inner.strip_prefix("@&") {
+        Token::Role(id.parse().ok()?)
+    } else if let Some(id) = inner.strip_prefix("@!").or_else(|| inner.strip_prefix('@')) {
+        Token::User(id.parse().ok()?)
+    } else if let Some(id) = inner.strip_prefix('#') {
+        Token::Channel(id.parse().ok()?)
+    } else {
+        let emoji = inner.strip_prefix("a:").or_else(|| inner.strip_prefix(':'))?;
+        let (name, id) = emoji.split_once(':')?;
+        id.parse::<u64>().ok()?;
+        Token::Emoji(name.to_string())
+    };
+
+    Some((token, end + 1))
+}
+
+/// Replace markup tokens in user text with readable names; `resolve` gives the name for a user,
+/// role or channel, and tokens it can't resolve are left as they are
+pub(crate) fn decode(text: &str, resolve: impl Fn(Token) -> Option<String>) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(pos) = rest.find('<') {
+        result.push_str(&rest[..pos]);
+        rest = &rest[pos..];
+
+        let Some((token, len)) = parse_token(rest) else {
+            result.push('<');
+            rest = &rest[1..];
+            continue;
+        };
+
+        let name = match &token {
+            Token::User(_) | Token::Role(_) => resolve(token.clone()).map(|n| format!("@{n}")),
+            Token::Channel(_) => resolve(token.clone()).map(|n| format!("#{n}")),
+            Token::Emoji(name) => Some(format!(":{name}:")),
+        };
+        result.push_str(name.as_deref().unwrap_or(&rest[..len]));
+        rest = &rest[len..];
+    }
+    result.push_str(rest);
+
+    result
+}
+
+/// Replace `@name` in model text with mentions of known users
+pub(crate) fn encode(text: &str, users: &HashMap<String, UserId>) -> String {
+    /* Try longer names first, so that "@Bob Smith" isn't taken as "@Bob" */
+    let mut names: Vec<_> = users.iter().collect();
+    names.sort_by_key(|(name, _)| std::cmp::Reverse(name.len()));
+
+    let mut result = String::with_capacity(text.len());
+    let mut rest = text;
+
+    while let Some(pos) = rest.find('@') {
+        result.push_str(&rest[..pos]);
+        let after = &rest[pos + 1..];
+
+        let found = names.iter().find(|(name, _)| {
+            after.starts_with(name.as_str())
+                && !after[name.len()..].starts_with(|c: char| c.is_alphanumeric() || c == '_')
+        });
+
+        match found {
+            Some((name, id)) => {
+                result.push_str(&format!("<@{id}>"));
+                rest = &after[name.len()..];
+            }
+            None => {
+                result.push('@');
+                rest = after;
+            }
+        }
+    }
+    result.push_str(rest);
+
+    result
+}
+
+/// Mentions the bot is allowed to make; users only, never roles, `@everyone` or `@here`
+pub(crate) fn allowed_mentions() -> CreateAllowedMentions {
+    CreateAllowedMentions::new()
+        .all_users(true)
+        .all_roles(false)
+        .everyone(false)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_decode() {
+        let resolve = |token| match token {
+            Token::User(1) => Some("Bob".to_string()),
+            Token::Role(2) => Some("Mods".to_string()),
+            Token::Channel(3) => Some("general".to_string()),
+            _ => None,
+        };
+
+        assert_eq!("hi @Bob and @Bob", decode("hi <@1> and <@!1>", resolve));
+        assert_eq!("ask @Mods in #general", decode("ask <@&2> in <#3>", resolve));
+        assert_eq!("nice :thumbs: :party:", decode("nice <:thumbs:44> <a:party:55>", resolve));
+        assert_eq!("who is <@9>?", decode("who is <@9>?", resolve));
+        assert_eq!("1 < 2 and <b>bold</b> <@x>", decode("1 < 2 and <b>bold</b> <@x>", resolve));
+    }
+
+    #[test]
+    fn test_encode() {
+        let users = HashMap::from([
+            ("Bob".to_string(), UserId::new(1)),
+            ("Bob Smith".to_string(), UserId::new(2)),
+        ]);
+
+        assert_eq!("hi <@1>!", encode("hi @Bob!", &users));
+        assert_eq!("hi <@2>", encode("hi @Bob Smith", &users));
+        assert_eq!("hi @Bobby and @everyone", encode("hi @Bobby and @everyone", &users));
+        assert_eq!("email bob@example.com", encode("email bob@example.com", &users));
+    }
+
+    #[test]
+    fn test_decode_encoded() {
+        /* Responses read back from Discord decode to what the model wrote */
+        let users = HashMap::from([
+            ("Bob".to_string(), UserId::new(1)),
+            ("Bob Smith".to_string(), UserId::new(2)),
+        ]);
+        let resolve = |token| match token {
+            Token::User(id) => users.iter().find(|(_, u)| u.get() == id).map(|(name, _)| name.clone()),
+            _ => None,
+        };
+
+        let text = "Hello @Bob Smith, @Bob and @everyone";
+        assert_eq!(text, decode(&encode(text, &users), resolve));
+    }
+}
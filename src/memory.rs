@@ -0,0 +1,178 @@
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::path::Path;
+use std::sync::Arc;
+
+use serde::{Deserialize, Serialize};
+
+use crate::dialogue::Part;
+
+/// Directory that guilds' notes are stored in, each in a file named by guild ID
+pub(crate) const GUILD_MEMORY_DIR: &str = "guild_memory";
+
+/// Directory that channels' notes are stored in, each in a file named by channel ID
+pub(crate) const CHANNEL_MEMORY_DIR: &str = "channel_memory";
+
+/// Longest note that will be remembered, in words
+pub(crate) const MAX_NOTE_LEN: u64 = 50;
+
+/// Most notes that a guild or channel can have
+pub(crate) const MAX_NOTES: usize = 20;
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    Empty,
+    TooLong(u64),
+    TooMany,
+    /// The note would leave too little of the prompt for the dialogue
+    NoRoom,
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::Empty => write!(f, "There is nothing to remember"),
+            Error::TooLong(len) => write!(f, "The note is too long ({len} words, maximum is {MAX_NOTE_LEN})"),
+            Error::TooMany => write!(f, "There are already {MAX_NOTES} notes; forget one first"),
+            Error::NoRoom => write!(f, "There isn't room for the note, as the prompt and notes must leave room for the conversation; forget a note first"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+/// Durable notes that are included in every prompt, regardless of dialogue truncation or reset
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Memory {
+    pub(crate) notes: Vec<String>,
+}
+
+/// Memory shared by all channels in a guild; a synchronous mutex, as it's read while assembling
+/// prompts
+pub(crate) type SharedMemory = Arc<std::sync::Mutex<Memory>>;
+
+impl Memory {
+    /// Add a note, unless it's too long or there are already too many; notes take up part of
+    /// every prompt, so leave less room for the dialogue
+    pub(crate) fn remember(&mut self, note: &str) -> Result<(), Error> {
+        let note = note.trim();
+        if note.is_empty() {
+            return Err(Error::Empty);
+        }
+        let len = note.split_whitespace().count() as u64;
+        if len > MAX_NOTE_LEN {
+            return Err(Error::TooLong(len));
+        }
+        if self.notes.len() >= MAX_NOTES {
+            return Err(Error::TooMany);
+        }
+        self.notes.push(note.to_string());
+        Ok(())
+    }
+
+    /// Forget a note by its 1-based number, as listed
+    pub(crate) fn forget(&mut self, number: usize) -> Option<String> {
+        if number == 0 || number > self.notes.len() {
+            return None;
+        }
+        Some(self.notes.remove(number - 1))
+    }
+
+    pub(crate) fn list(&self) -> String {
+        self.notes.iter().enumerate()
+            .map(|(i, note)| format!("{}. {note}\n", i + 1))
+            .collect()
+    }
+
+    /// The notes as a single part for the prompt
+    pub(crate) fn to_part(&self) -> Option<Part> {
+        if self.notes.is_empty() {
+            return None;
+        }
+
+        let mut text = "Things to remember:\n".to_string();
+        for note in &self.notes {
+            text.push_str("- ");
+            text.push_str(note);
+            text.push('\n');
+        }
+
+        Some(Part {
+            role: "user".to_string(),
+            text,
+            message_ids: Vec::new(),
+            template: false,
+        })
+    }
+
+    pub(crate) fn total_len(&self) -> u64 {
+        self.to_part().map_or(0, |p| p.len())
+    }
+}
+
+/// Load the notes saved under an ID, a guild's or a channel's; `None` if none have been saved
+pub(crate) fn load_memory(dir: impl AsRef<Path>, id: impl Display) -> Result<Option<Memory>, std::io::Error> {
+    let text = match std::fs::read_to_string(dir.as_ref().join(format!("{id}.json"))) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err),
+    };
+    serde_json::from_str(&text).map(Some).map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))
+}
+
+pub(crate) fn save_memory(dir: impl AsRef<Path>, id: impl Display, memory: &Memory) -> Result<(), std::io::Error> {
+    std::fs::create_dir_all(&dir)?;
+    let text = serde_json::to_string_pretty(memory)?;
+    std::fs::write(dir.as_ref().join(format!("{id}.json")), text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_remember_and_forget() {
+        let mut memory = Memory::default();
+        assert!(memory.to_part().is_none());
+        assert_eq!(0, memory.total_len());
+
+        memory.remember("Bob is an elf").unwrap();
+        memory.remember(" Alice is a dwarf ").unwrap();
+        assert_eq!("1. Bob is an elf\n2. Alice is a dwarf\n", memory.list());
+
+        let part = memory.to_part().unwrap();
+        assert_eq!("Things to remember:\n- Bob is an elf\n- Alice is a dwarf\n", part.text);
+
+        assert_eq!(None, memory.forget(0));
+        assert_eq!(None, memory.forget(3));
+        assert_eq!(Some("Bob is an elf".to_string()), memory.forget(1));
+        assert_eq!("1. Alice is a dwarf\n", memory.list());
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut memory = Memory::default();
+        assert!(matches!(memory.remember("  "), Err(Error::Empty)));
+        let long_note = "word ".repeat(MAX_NOTE_LEN as usize + 1);
+        assert!(matches!(memory.remember(&long_note), Err(Error::TooLong(51))));
+
+        for i in 0..MAX_NOTES {
+            memory.remember(&format!("note {i}")).unwrap();
+        }
+        assert!(matches!(memory.remember("one more"), Err(Error::TooMany)));
+        assert_eq!(MAX_NOTES, memory.notes.len());
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("clutha-memory-test-{}", std::process::id()));
+        assert!(load_memory(&dir, 1).unwrap().is_none());
+
+        let mut memory = Memory::default();
+        memory.remember("Bob is an elf").unwrap();
+        save_memory(&dir, 1, &memory).unwrap();
+        assert_eq!(vec!["Bob is an elf"], load_memory(&dir, 1).unwrap().unwrap().notes);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
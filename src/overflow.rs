@@ -0,0 +1,127 @@
+use std::mem::take;
+
+use crate::markdown::{parse_blocks, split_block, split_markdown, Block};
+
+/// Prose that would take more than this many messages is sent as a file instead
+const MAX_PROSE_SEGMENTS: usize = 3;
+
+/// Part of a response, sent as a message of its own
+#[derive(Clone, Debug, PartialEq)]
+pub(crate) enum Segment {
+    Text(String),
+    /// Text too long for a message, sent as a file
+    Attachment { filename: String, content: String },
+}
+
+impl Segment {
+    pub(crate) fn len(&self) -> usize {
+        match self {
+            Segment::Text(text) => text.len(),
+            Segment::Attachment { content, .. } => content.len(),
+        }
+    }
+}
+
+/// Split a response into segments of at most `max_size` bytes; blocks too large for one
+/// message are sent some other way, rather than split into lots of messages
+pub(crate) fn split_response(result: &str, max_size: usize) -> Vec<Segment> {
+    let mut segments = Vec::new();
+    let mut text = String::new();
+    for block in parse_blocks(result) {
+        let attachment = if block.text.len() <= max_size {
+            None
+        } else {
+            overflow(&block, max_size)
+        };
+        let Some(attachment) = attachment else {
+            text.push_str(block.text);
+            continue;
+        };
+
+        segments.extend(split_markdown(&take(&mut text), max_size).into_iter().map(Segment::Text));
+        segments.push(attachment);
+    }
+    segments.extend(split_markdown(&text, max_size).into_iter().map(Segment::Text));
+
+    segments
+}
+
+/// The attachment for a block that's too large for one message, if it shouldn't just be split: a
+/// code block becomes a file named for its language, and prose becomes a markdown file if it would
+/// take too many messages
+fn overflow(block: &Block, max_size: usize) -> Option<Segment> {
+    if let (Some(fence), Some(code)) = (block.fence, block.code()) {
+        return Some(Segment::Attachment {
+            filename: format!("code.{}", extension(fence.language)),
+            content: code.to_string(),
+        });
+    }
+
+    if split_block(block, max_size).len() > MAX_PROSE_SEGMENTS {
+        return Some(Segment::Attachment {
+            filename: "response.md".to_string(),
+            content: block.text.to_string(),
+        });
+    }
+    None
+}
+
+/// File extension for code in a fenced block's language
+fn extension(language: &str) -> &'static str {
+    match language.to_lowercase().as_str() {
+        "rust" | "rs" => "rs",
+        "python" | "py" => "py",
+        "javascript" | "js" => "js",
+        "typescript" | "ts" => "ts",
+        "c" => "c",
+        "cpp" | "c++" => "cpp",
+        "csharp" | "cs" | "c#" => "cs",
+        "java" => "java",
+        "kotlin" | "kt" => "kt",
+        "go" | "golang" => "go",
+        "ruby" | "rb" => "rb",
+        "php" => "php",
+        "swift" => "swift",
+        "sh" | "bash" | "shell" | "zsh" => "sh",
+        "powershell" | "ps1" => "ps1",
+        "sql" => "sql",
+        "html" => "html",
+        "css" => "css",
+        "xml" => "xml",
+        "json" => "json",
+        "yaml" | "yml" => "yaml",
+        "toml" => "toml",
+        "markdown" | "md" => "md",
+        _ => "txt",
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_split_response() {
+        let code = format!("```Rust\n{}```\n", "let x = 1;\n".repeat(20));
+        let segments = split_response(&format!("Here it is:\n\n{code}That's all."), 100);
+        assert_eq!(vec![
+            Segment::Text("Here it is:\n\n".to_string()),
+            Segment::Attachment { filename: "code.rs".to_string(), content: "let x = 1;\n".repeat(20) },
+            Segment::Text("That's all.".to_string()),
+        ], segments);
+
+        /* Plain prose has no formatting to make room for, so each segment is filled */
+        let prose = "This is a sentence. ".repeat(10);
+        let segments = split_response(&prose, 100);
+        assert_eq!(vec![Segment::Text("This is a sentence. ".repeat(5)); 2], segments);
+
+        let prose = format!("**{}**", "This is a sentence. ".repeat(10));
+        let segments = split_response(&prose, 100);
+        assert_eq!(3, segments.len());
+        assert!(segments.iter().all(|s| matches!(s, Segment::Text(t) if t.len() <= 100 && t.starts_with("**"))), "{segments:?}");
+
+        let prose = "This is a sentence. ".repeat(100);
+        let segments = split_response(&prose, 100);
+        assert_eq!(vec![Segment::Attachment { filename: "response.md".to_string(), content: prose }], segments);
+    }
+}
@@ -0,0 +1,132 @@
+use std::collections::{BTreeMap, BTreeSet};
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+use serenity::all::{GuildId, RoleId};
+
+/// Directory that guilds' permissions are stored in, each in a file named by guild ID
+pub(crate) const GUILD_PERMISSIONS_DIR: &str = "guild_permissions";
+
+/// Something that a guild can restrict to members with certain roles
+#[derive(Clone, Copy, Debug, PartialEq, Eq, PartialOrd, Ord, Serialize, Deserialize, poise::ChoiceParameter)]
+#[serde(rename_all = "lowercase")]
+pub(crate) enum Capability {
+    /// Change a channel's mode
+    #[name = "mode"]
+    Mode,
+    /// Set or import a channel's prompt
+    #[name = "prompt"]
+    Prompt,
+    /// Reset, undo, rewind, correct or backfill a channel's dialogue
+    #[name = "reset"]
+    Reset,
+    /// Add or remove remembered notes
+    #[name = "memory"]
+    Memory,
+}
+
+impl Capability {
+    pub(crate) const ALL: [Capability; 4] = [Capability::Mode, Capability::Prompt, Capability::Reset, Capability::Memory];
+}
+
+impl Display for Capability {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        write!(f, "{}", poise::ChoiceParameter::name(self))
+    }
+}
+
+/// The roles that have each capability in a guild; a capability with no roles is open to everyone
+#[derive(Clone, Debug, Default, Serialize, Deserialize)]
+pub(crate) struct Permissions {
+    roles: BTreeMap<Capability, BTreeSet<RoleId>>,
+}
+
+impl Permissions {
+    /// Give a role a capability; returns whether it didn't already have it
+    pub(crate) fn grant(&mut self, capability: Capability, role_id: RoleId) -> bool {
+        self.roles.entry(capability).or_default().insert(role_id)
+    }
+
+    /// Take a capability from a role; returns whether it had it
+    pub(crate) fn revoke(&mut self, capability: Capability, role_id: RoleId) -> bool {
+        let Some(roles) = self.roles.get_mut(&capability) else {
+            return false;
+        };
+        let removed = roles.remove(&role_id);
+        if roles.is_empty() {
+            self.roles.remove(&capability);
+        }
+        removed
+    }
+
+    /// Roles with the capability; empty if it's open to everyone
+    pub(crate) fn roles(&self, capability: Capability) -> Vec<RoleId> {
+        self.roles.get(&capability).map(|r| r.iter().copied().collect()).unwrap_or_default()
+    }
+
+    /// Whether a member with the given roles has the capability
+    pub(crate) fn allows(&self, capability: Capability, member_roles: &[RoleId]) -> bool {
+        match self.roles.get(&capability) {
+            Some(roles) => member_roles.iter().any(|r| roles.contains(r)),
+            None => true,
+        }
+    }
+}
+
+/// Load a guild's permissions; a guild that hasn't set any has the defaults
+pub(crate) fn load_permissions(dir: impl AsRef<Path>, guild_id: GuildId) -> Result<Permissions, std::io::Error> {
+    let text = match std::fs::read_to_string(dir.as_ref().join(format!("{guild_id}.json"))) {
+        Ok(text) => text,
+        Err(err) if err.kind() == ErrorKind::NotFound => return Ok(Permissions::default()),
+        Err(err) => return Err(err),
+    };
+    serde_json::from_str(&text).map_err(|err| std::io::Error::new(ErrorKind::InvalidData, err))
+}
+
+pub(crate) fn save_permissions(dir: impl AsRef<Path>, guild_id: GuildId, permissions: &Permissions) -> Result<(), std::io::Error> {
+    std::fs::create_dir_all(&dir)?;
+    let text = serde_json::to_string_pretty(permissions)?;
+    std::fs::write(dir.as_ref().join(format!("{guild_id}.json")), text)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_permissions() {
+        let mods = RoleId::new(1);
+        let regulars = RoleId::new(2);
+        let mut permissions = Permissions::default();
+        assert!(permissions.allows(Capability::Mode, &[]));
+
+        assert!(permissions.grant(Capability::Mode, mods));
+        assert!(!permissions.grant(Capability::Mode, mods));
+        assert!(permissions.allows(Capability::Mode, &[regulars, mods]));
+        assert!(!permissions.allows(Capability::Mode, &[regulars]));
+        assert!(permissions.allows(Capability::Reset, &[regulars]));
+
+        assert!(!permissions.revoke(Capability::Mode, regulars));
+        assert!(permissions.revoke(Capability::Mode, mods));
+        assert!(permissions.allows(Capability::Mode, &[]));
+    }
+
+    #[test]
+    fn test_save_and_load() {
+        let dir = std::env::temp_dir().join(format!("clutha-permissions-test-{}", std::process::id()));
+        let guild_id = GuildId::new(3);
+        assert!(load_permissions(&dir, guild_id).unwrap().roles.is_empty());
+
+        let mut permissions = Permissions::default();
+        permissions.grant(Capability::Memory, RoleId::new(4));
+        save_permissions(&dir, guild_id, &permissions).unwrap();
+
+        let loaded = load_permissions(&dir, guild_id).unwrap();
+        assert_eq!(vec![RoleId::new(4)], loaded.roles(Capability::Memory));
+        assert!(loaded.roles(Capability::Prompt).is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
@@ -1,40 +1,426 @@
-use std::io::BufReader;
+use std::collections::BTreeMap;
 use std::path::Path;
 
-use crate::dialogue::{read_dialogue, Dialogue};
+use tracing::warn;
+
+use crate::backend::GenerationOptions;
+use crate::channel::Mode;
+use crate::dialogue::{read_dialogue, write_dialogue, Dialogue};
+use crate::source::{error_at, split_lines, Line};
+use crate::store;
+use crate::store::{PromptStore, MAX_PROMPT_SIZE};
+use crate::template::unknown_variables;
 
 #[derive(Clone, Debug, Default)]
 pub(crate) struct Prompt {
     pub(crate) prompt: Dialogue,
     pub(crate) initial: Dialogue,
     pub(crate) filename: String,
+    /// Name the prompt was loaded by from a store, so it can be reloaded
+    pub(crate) name: Option<String>,
+    pub(crate) metadata: Metadata,
+}
+
+/// Optional settings from a prompt file's header
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Metadata {
+    pub(crate) name: Option<String>,
+    pub(crate) description: Option<String>,
+    pub(crate) author: Option<String>,
+    /// Mode to put the channel in when the prompt is set
+    pub(crate) mode: Option<Mode>,
+    /// Backend the prompt is written for
+    pub(crate) backend: Option<String>,
+    pub(crate) generation: GenerationOptions,
+    /// Responses longer than this are put in a new thread
+    pub(crate) thread_threshold: Option<usize>,
+    /// Whether the prompt gets its own command to set it
+    pub(crate) command: bool,
+}
+
+/// Directory that prompt files are loaded from
+pub(crate) const PROMPT_DIR: &str = "prompts";
+
+/// Directory that guilds' uploaded prompts are stored in, each in a subdirectory named by guild ID
+pub(crate) const GUILD_PROMPT_DIR: &str = "guild_prompts";
+
+/// Subdirectory of the prompt directory that fragments for includes are loaded from
+pub(crate) const FRAGMENT_DIR: &str = "fragments";
+
+/// Prompt used for channels when they're first seen
+pub(crate) const DEFAULT_PROMPT: &str = "default";
+
+/// All the prompts available in a store, by name
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Catalogue {
+    pub(crate) prompts: BTreeMap<String, Prompt>,
+}
+
+impl Catalogue {
+    /// Load every prompt in the store; prompts that can't be read are skipped
+    pub(crate) fn load(store: &PromptStore) -> Result<Catalogue, store::Error> {
+        let mut prompts = BTreeMap::new();
+
+        for name in store.names()? {
+            match store.load(&name) {
+                Ok(prompt) => {
+                    let unknown = prompt.unknown_variables();
+                    if !unknown.is_empty() {
+                        warn!("Prompt {name} has unknown variables: {}", unknown.join(", "));
+                    }
+                    prompts.insert(name, prompt);
+                }
+                Err(err) => warn!("Skipping prompt {name}: {err}"),
+            }
+        }
+
+        Ok(Catalogue { prompts })
+    }
+
+    /// Names of the prompts starting with the given text
+    pub(crate) fn matching<'a>(&'a self, partial: &'a str) -> impl Iterator<Item = &'a str> {
+        self.prompts.keys()
+            .map(String::as_str)
+            .filter(move |name| name.starts_with(partial))
+    }
+
+    /// Prompts that have their own command
+    pub(crate) fn commands(&self) -> impl Iterator<Item = (&str, &Prompt)> {
+        self.prompts.iter()
+            .filter(|(_, prompt)| prompt.metadata.command)
+            .map(|(name, prompt)| (name.as_str(), prompt))
+    }
+}
+
+impl Prompt {
+    /// Whether the initial dialogue ends with the user, so the model should respond to it
+    pub(crate) fn needs_response(&self) -> bool {
+        self.initial.parts.back().is_some_and(|p| p.role == "user")
+    }
+
+    /// Clear any backend and generation settings, so that the operator's are used; returns
+    /// whether there were any
+    pub(crate) fn drop_backend_settings(&mut self) -> bool {
+        let had_settings = self.metadata.backend.is_some() || self.metadata.generation != GenerationOptions::default();
+        self.metadata.backend = None;
+        self.metadata.generation = GenerationOptions::default();
+        had_settings
+    }
+
+    /// Template placeholders in the prompt that aren't known variables
+    pub(crate) fn unknown_variables(&self) -> Vec<String> {
+        self.prompt.parts.iter()
+            .chain(self.initial.parts.iter())
+            .flat_map(|p| unknown_variables(&p.text))
+            .collect()
+    }
 }
 
-pub(crate) fn load_prompt(path: impl AsRef<Path>) -> Result<Prompt, std::io::Error> {
+/// Line that starts and ends the header
+const HEADER_DELIMITER: &str = "+++";
+
+/// Line that includes a fragment from the fragment store, e.g. `!include house_rules`
+const INCLUDE_DIRECTIVE: &str = "!include";
+
+/// Deepest that includes can be nested
+const MAX_INCLUDE_DEPTH: usize = 8;
+
+pub(crate) fn load_prompt(path: impl AsRef<Path>, fragments: Option<&PromptStore>) -> Result<Prompt, std::io::Error> {
     let filename = path.as_ref().as_os_str().to_str().unwrap_or("").to_owned();
-    let f = std::fs::File::open(path)?;
-    let mut f = BufReader::new(f);
+    let text = std::fs::read_to_string(path)?;
+
+    parse_prompt(&text, &filename, fragments)
+}
 
-    let prompt = read_dialogue(&mut f)?;
-    let initial = read_dialogue(&mut f)?;
+/// Parse a prompt file's text, resolving any includes from the fragment store.
+///
+/// A prompt file has an optional header, then the prompt dialogue, then a `---` line, then the
+/// initial dialogue; see `read_dialogue` for how dialogue is written.
+pub(crate) fn parse_prompt(text: &str, filename: &str, fragments: Option<&PromptStore>) -> Result<Prompt, std::io::Error> {
+    let mut lines = expand_includes(text, filename, fragments, &mut Vec::new())?
+        .into_iter()
+        .peekable();
+
+    let metadata = if lines.peek().is_some_and(|l| l.text.starts_with(HEADER_DELIMITER)) {
+        read_metadata(&mut lines)?
+    } else {
+        Metadata::default()
+    };
+    let prompt = read_dialogue(&mut lines)?;
+    let initial = read_dialogue(&mut lines)?;
 
     Ok(Prompt {
         prompt,
         initial,
-        filename,
+        filename: filename.to_owned(),
+        name: None,
+        metadata,
     })
 }
 
+/// Split the text into lines, replacing each include line with the lines of the fragment it
+/// names, recursively; `stack` holds the names of the fragments currently being included, to
+/// detect cycles
+fn expand_includes(text: &str, filename: &str, fragments: Option<&PromptStore>, stack: &mut Vec<String>) -> Result<Vec<Line>, std::io::Error> {
+    let mut result = Vec::new();
+    let mut size = 0;
+
+    for line in split_lines(text, filename) {
+        let Some(rest) = line.text.strip_prefix(INCLUDE_DIRECTIVE).filter(|r| r.is_empty() || r.starts_with(char::is_whitespace)) else {
+            size += line.text.len() + 1;
+            result.push(line);
+            continue;
+        };
+
+        let name = rest.trim();
+        if name.is_empty() {
+            return Err(line.error(format!("{INCLUDE_DIRECTIVE} needs a fragment name")));
+        }
+        let Some(fragments) = fragments else {
+            return Err(line.error(format!("can't include '{name}': fragments aren't available here")));
+        };
+        if stack.iter().any(|n| n == name) {
+            return Err(line.error(format!("include cycle: {} -> {name}", stack.join(" -> "))));
+        }
+        if stack.len() >= MAX_INCLUDE_DEPTH {
+            return Err(line.error(format!("can't include '{name}': includes are nested more than {MAX_INCLUDE_DEPTH} deep")));
+        }
+
+        let fragment = match fragments.read(name) {
+            Ok(fragment) => fragment,
+            Err(store::Error::NotFound(_)) => return Err(line.error(format!("there is no fragment called '{name}'"))),
+            Err(err) => return Err(line.error(format!("can't include '{name}': {err}"))),
+        };
+
+        stack.push(name.to_string());
+        let expanded = expand_includes(&fragment, &fragments.filename(name), Some(fragments), stack)?;
+        stack.pop();
+
+        size += expanded.iter().map(|l| l.text.len() + 1).sum::<usize>();
+        if size as u64 > MAX_PROMPT_SIZE {
+            return Err(line.error(format!("prompt is too large after including '{name}' (maximum is {MAX_PROMPT_SIZE} bytes)")));
+        }
+        result.extend(expanded);
+    }
+
+    Ok(result)
+}
+
+/// Read the header, from its opening delimiter line to its closing one; each line in between is
+/// a `key: value` pair
+fn read_metadata(lines: &mut impl Iterator<Item = Line>) -> Result<Metadata, std::io::Error> {
+    let mut metadata = Metadata::default();
+    let Some(mut last) = lines.next() else {
+        return Ok(metadata);
+    };
+
+    loop {
+        let Some(line) = lines.next() else {
+            return Err(error_at(&last.filename, last.number + 1, format!("missing closing {HEADER_DELIMITER}")));
+        };
+        let text = line.text.trim();
+        if text == HEADER_DELIMITER {
+            break;
+        }
+        if text.is_empty() || text.starts_with('#') {
+            last = line;
+            continue;
+        }
+
+        let Some((key, value)) = text.split_once(':') else {
+            return Err(line.error(format!("expected 'key: value', found '{text}'")));
+        };
+        let value = value.trim();
+        let bad_value = || line.error(format!("invalid value for {key}: '{value}'"));
+
+        match key.trim() {
+            "name" => metadata.name = Some(value.to_string()),
+            "description" => metadata.description = Some(value.to_string()),
+            "author" => metadata.author = Some(value.to_string()),
+            "mode" => metadata.mode = Some(Mode::try_from(value).map_err(|_| bad_value())?),
+            "backend" => metadata.backend = Some(value.to_string()),
+            /* The model is part of the backend's URL, so mustn't be able to change the rest of it */
+            "model" if !is_model_name(value) => return Err(bad_value()),
+            "model" => metadata.generation.model = Some(value.to_string()),
+            "temperature" => metadata.generation.temperature = Some(value.parse().map_err(|_| bad_value())?),
+            "top_p" => metadata.generation.top_p = Some(value.parse().map_err(|_| bad_value())?),
+            "max_output_tokens" => metadata.generation.max_output_tokens = Some(value.parse().map_err(|_| bad_value())?),
+            "thread_threshold" => metadata.thread_threshold = Some(value.parse().map_err(|_| bad_value())?),
+            "command" => metadata.command = value.parse().map_err(|_| bad_value())?,
+            other => return Err(line.error(format!("unknown header field '{other}'"))),
+        }
+        last = line;
+    }
+
+    Ok(metadata)
+}
+
+/// Whether a model name is of the form `models/<name>`, where the name is only letters, digits,
+/// '.' and '-'
+fn is_model_name(model: &str) -> bool {
+    model.strip_prefix("models/")
+        .is_some_and(|name| !name.is_empty() && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '.' || c == '-'))
+}
+
+fn write_metadata(metadata: &Metadata) -> String {
+    let fields = [
+        ("name", metadata.name.clone()),
+        ("description", metadata.description.clone()),
+        ("author", metadata.author.clone()),
+        ("mode", metadata.mode.map(|m| format!("{m:?}").to_lowercase())),
+        ("backend", metadata.backend.clone()),
+        ("model", metadata.generation.model.clone()),
+        ("temperature", metadata.generation.temperature.map(|v| v.to_string())),
+        ("top_p", metadata.generation.top_p.map(|v| v.to_string())),
+        ("max_output_tokens", metadata.generation.max_output_tokens.map(|v| v.to_string())),
+        ("thread_threshold", metadata.thread_threshold.map(|v| v.to_string())),
+        ("command", metadata.command.then(|| "true".to_string())),
+    ];
+
+    let lines: String = fields.iter()
+        .filter_map(|(key, value)| Some(format!("{key}: {}\n", value.as_ref()?)))
+        .collect();
+    if lines.is_empty() {
+        return lines;
+    }
+    format!("{HEADER_DELIMITER}\n{lines}{HEADER_DELIMITER}\n")
+}
+
+/// Write a prompt in the same format that `parse_prompt` reads
+pub(crate) fn write_prompt(prompt: &Prompt) -> String {
+    format!(
+        "{}{}---\n\n{}",
+        write_metadata(&prompt.metadata),
+        write_dialogue(&prompt.prompt),
+        write_dialogue(&prompt.initial),
+    )
+}
+
 #[cfg(test)]
 mod test {
     use super::*;
 
     #[test]
     fn test_load_prompt() {
-        let p = load_prompt("prompts/about.txt").unwrap();
+        let p = load_prompt("prompts/about.txt", None).unwrap();
 
         assert_eq!("prompts/about.txt", p.filename);
         assert_eq!(304, p.prompt.total_len);
         assert_eq!(2, p.initial.total_len);
     }
+
+    #[test]
+    fn test_write_prompt() {
+        let fragments = PromptStore::open("prompts/fragments").unwrap();
+        let p = load_prompt("prompts/default.txt", Some(&fragments)).unwrap();
+        let text = write_prompt(&p);
+
+        let p2 = parse_prompt(&text, "copy", None).unwrap();
+        assert_eq!(p.prompt.total_len, p2.prompt.total_len);
+        assert_eq!(p.initial.total_len, p2.initial.total_len);
+        assert_eq!("user", p2.initial.parts[0].role);
+    }
+
+    #[test]
+    fn test_read_metadata() {
+        const TEST_PROMPT: &str = "+++
+name: Cluthor
+description: An evil necromancer
+mode: active
+temperature: 1.5
+thread_threshold: 500
++++
+You are Cluthor.
+---
+> Hello
+";
+        let p = parse_prompt(TEST_PROMPT, "test", None).unwrap();
+        assert_eq!(Some("Cluthor".to_string()), p.metadata.name);
+        assert_eq!(Some("An evil necromancer".to_string()), p.metadata.description);
+        assert!(matches!(p.metadata.mode, Some(Mode::Active)));
+        assert_eq!(Some(1.5), p.metadata.generation.temperature);
+        assert_eq!(Some(500), p.metadata.thread_threshold);
+        assert_eq!("You are Cluthor.\n", p.prompt.parts[0].text);
+        assert_eq!("user", p.initial.parts[0].role);
+
+        let text = write_prompt(&p);
+        let p2 = parse_prompt(&text, "copy", None).unwrap();
+        assert_eq!(p.metadata.name, p2.metadata.name);
+        assert_eq!(p.metadata.generation, p2.metadata.generation);
+    }
+
+    #[test]
+    fn test_catalogue() {
+        let fragments = PromptStore::open("prompts/fragments").ok();
+        let catalogue = Catalogue::load(&PromptStore::open(PROMPT_DIR).unwrap().with_fragments(fragments)).unwrap();
+
+        assert!(catalogue.prompts.contains_key("default"));
+        assert_eq!(vec!["about"], catalogue.matching("ab").collect::<Vec<_>>());
+
+        let commands: Vec<_> = catalogue.commands().map(|(name, _)| name).collect();
+        assert_eq!(vec!["about", "default"], commands);
+    }
+
+    #[test]
+    fn test_read_metadata_errors() {
+        let err = parse_prompt("+++\nname: x\ncolour: red\n+++\n", "test", None).unwrap_err();
+        assert_eq!("test:3: unknown header field 'colour'", err.to_string());
+
+        let err = parse_prompt("+++\nmode: sideways\n+++\n", "test", None).unwrap_err();
+        assert_eq!("test:2: invalid value for mode: 'sideways'", err.to_string());
+
+        for model in ["gemini-2.5-flash", "models/../files", "models/x?key=y", "models/x#y", "models/"] {
+            let err = parse_prompt(&format!("+++\nmodel: {model}\n+++\n"), "test", None).unwrap_err();
+            assert_eq!(format!("test:2: invalid value for model: '{model}'"), err.to_string());
+        }
+        assert!(parse_prompt("+++\nmodel: models/gemini-2.5-flash\n+++\n", "test", None).is_ok());
+
+        let err = parse_prompt("+++\nname: x\n", "test", None).unwrap_err();
+        assert_eq!("test:3: missing closing +++", err.to_string());
+    }
+
+    #[test]
+    fn test_includes() {
+        let dir = std::env::temp_dir().join(format!("clutha-include-test-{}", std::process::id()));
+        let fragments = PromptStore::create(&dir).unwrap();
+        fragments.save("rules", "Be brief.\n!include politeness\n").unwrap();
+        fragments.save("politeness", "Be polite.\n").unwrap();
+        fragments.save("chicken", "!include egg\n").unwrap();
+        fragments.save("egg", "!include chicken\n").unwrap();
+        let fragments = Some(&fragments);
+
+        let p = parse_prompt("You are a test.\n!include rules\n---\n> Hi\n", "test", fragments).unwrap();
+        assert_eq!("You are a test.\nBe brief.\nBe polite.\n", p.prompt.parts[0].text);
+        assert_eq!("user", p.initial.parts[0].role);
+
+        let err = parse_prompt("Hello\n!include nothing\n", "test", fragments).unwrap_err();
+        assert_eq!("test:2: there is no fragment called 'nothing'", err.to_string());
+
+        let err = parse_prompt("!include chicken\n", "test", fragments).unwrap_err();
+        assert!(err.to_string().ends_with("egg.txt:1: include cycle: chicken -> egg -> chicken"), "{err}");
+
+        let err = parse_prompt("!include ../secret\n", "test", fragments).unwrap_err();
+        assert!(err.to_string().starts_with("test:1: can't include '../secret': "), "{err}");
+
+        let err = parse_prompt("!include rules\n", "test", None).unwrap_err();
+        assert_eq!("test:1: can't include 'rules': fragments aren't available here", err.to_string());
+
+        assert!(parse_prompt("!included is just text\n", "test", None).is_ok());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_include_depth() {
+        let dir = std::env::temp_dir().join(format!("clutha-depth-test-{}", std::process::id()));
+        let fragments = PromptStore::create(&dir).unwrap();
+        for i in 0..MAX_INCLUDE_DEPTH + 1 {
+            fragments.save(&format!("level{i}"), &format!("!include level{}\n", i + 1)).unwrap();
+        }
+
+        let err = parse_prompt("!include level0\n", "test", Some(&fragments)).unwrap_err();
+        assert!(err.to_string().contains("nested more than"), "{err}");
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
 }
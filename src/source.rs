@@ -0,0 +1,34 @@
+use std::fmt::Display;
+use std::io::ErrorKind;
+
+/// A line of a prompt file, with where it came from so errors can point at it
+#[derive(Clone, Debug)]
+pub(crate) struct Line {
+    pub(crate) filename: String,
+    /// Line number in the file, starting from 1
+    pub(crate) number: usize,
+    pub(crate) text: String,
+}
+
+impl Line {
+    /// An error about this line, located as `file:line`
+    pub(crate) fn error(&self, message: impl Display) -> std::io::Error {
+        error_at(&self.filename, self.number, message)
+    }
+}
+
+pub(crate) fn error_at(filename: &str, number: usize, message: impl Display) -> std::io::Error {
+    std::io::Error::new(ErrorKind::InvalidData, format!("{filename}:{number}: {message}"))
+}
+
+/// Split a file's text into lines
+pub(crate) fn split_lines(text: &str, filename: &str) -> Vec<Line> {
+    text.lines()
+        .enumerate()
+        .map(|(i, text)| Line {
+            filename: filename.to_string(),
+            number: i + 1,
+            text: text.to_string(),
+        })
+        .collect()
+}
@@ -0,0 +1,300 @@
+use std::fmt::{Display, Formatter};
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
+use std::time::SystemTime;
+
+use crate::prompt::{load_prompt, Prompt};
+
+/// Largest prompt file that will be read, in bytes
+pub(crate) const MAX_PROMPT_SIZE: u64 = 64 * 1024;
+
+/// Longest allowed prompt name
+const MAX_NAME_LEN: usize = 64;
+
+const EXTENSION: &str = "txt";
+
+#[derive(Debug)]
+pub(crate) enum Error {
+    InvalidName(String),
+    NotFound(String),
+    TooLarge(String, u64),
+    Io(std::io::Error),
+}
+
+impl Display for Error {
+    fn fmt(&self, f: &mut Formatter<'_>) -> std::fmt::Result {
+        match self {
+            Error::InvalidName(name) => write!(f, "'{name}' is not a valid prompt name; use up to {MAX_NAME_LEN} letters, digits, '-' or '_'"),
+            Error::NotFound(name) => write!(f, "There is no prompt called '{name}'"),
+            Error::TooLarge(name, size) => write!(f, "Prompt '{name}' is too large ({size} bytes, maximum is {MAX_PROMPT_SIZE})"),
+            Error::Io(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl std::error::Error for Error {}
+
+impl From<std::io::Error> for Error {
+    fn from(value: std::io::Error) -> Self {
+        Error::Io(value)
+    }
+}
+
+/// Check that a user-supplied prompt name can safely be used as a file name
+pub(crate) fn validate_name(name: &str) -> Result<(), Error> {
+    let valid = !name.is_empty()
+        && name.len() <= MAX_NAME_LEN
+        && name.chars().all(|c| c.is_ascii_alphanumeric() || c == '-' || c == '_');
+    if !valid {
+        return Err(Error::InvalidName(name.to_string()));
+    }
+    Ok(())
+}
+
+/// A directory of prompt files, which are only accessed by validated names
+#[derive(Clone, Debug)]
+pub(crate) struct PromptStore {
+    /// The directory as given, for display
+    dir: String,
+    /// The canonical directory, that all prompt files must be in
+    root: PathBuf,
+    /// Store that `!include` lines in this store's prompts are resolved from
+    fragments: Option<Box<PromptStore>>,
+    /// Whether prompts can choose the backend and generation settings, such as the model
+    backend_settings: bool,
+}
+
+impl PromptStore {
+    pub(crate) fn open(dir: impl AsRef<Path>) -> Result<PromptStore, Error> {
+        let root = dir.as_ref().canonicalize()?;
+        Ok(PromptStore {
+            dir: dir.as_ref().display().to_string(),
+            root,
+            fragments: None,
+            backend_settings: true,
+        })
+    }
+
+    /// Resolve includes in this store's prompts from the given store
+    pub(crate) fn with_fragments(self, fragments: Option<PromptStore>) -> PromptStore {
+        PromptStore {
+            fragments: fragments.map(Box::new),
+            ..self
+        }
+    }
+
+    /// Ignore the backend and generation settings of this store's prompts, which aren't the
+    /// operator's to choose
+    pub(crate) fn without_backend_settings(self) -> PromptStore {
+        PromptStore {
+            backend_settings: false,
+            ..self
+        }
+    }
+
+    pub(crate) fn fragments(&self) -> Option<&PromptStore> {
+        self.fragments.as_deref()
+    }
+
+    /// Open the store, creating its directory if it doesn't exist
+    pub(crate) fn create(dir: impl AsRef<Path>) -> Result<PromptStore, Error> {
+        std::fs::create_dir_all(&dir)?;
+        PromptStore::open(dir)
+    }
+
+    /// Path of an existing prompt file, which is checked to be inside the store
+    fn path(&self, name: &str) -> Result<PathBuf, Error> {
+        validate_name(name)?;
+
+        let path = self.root.join(format!("{name}.{EXTENSION}"));
+        let path = match path.canonicalize() {
+            Ok(path) => path,
+            Err(err) if err.kind() == ErrorKind::NotFound => return Err(Error::NotFound(name.to_string())),
+            Err(err) => return Err(err.into()),
+        };
+
+        /* A symlink could lead anywhere */
+        if !path.starts_with(&self.root) || !path.is_file() {
+            return Err(Error::NotFound(name.to_string()));
+        }
+
+        Ok(path)
+    }
+
+    /// Path of a prompt file as shown to users
+    pub(crate) fn filename(&self, name: &str) -> String {
+        format!("{}/{name}.{EXTENSION}", self.dir)
+    }
+
+    /// Path of an existing prompt file that isn't too large to read
+    fn checked_path(&self, name: &str) -> Result<PathBuf, Error> {
+        let path = self.path(name)?;
+
+        let size = std::fs::metadata(&path)?.len();
+        if size > MAX_PROMPT_SIZE {
+            return Err(Error::TooLarge(name.to_string(), size));
+        }
+
+        Ok(path)
+    }
+
+    pub(crate) fn load(&self, name: &str) -> Result<Prompt, Error> {
+        let path = self.checked_path(name)?;
+
+        let mut prompt = load_prompt(&path, self.fragments())?;
+        prompt.filename = self.filename(name);
+        prompt.name = Some(name.to_string());
+        if !self.backend_settings {
+            prompt.drop_backend_settings();
+        }
+        Ok(prompt)
+    }
+
+    /// Read a prompt file's text as it is, without parsing it
+    pub(crate) fn read(&self, name: &str) -> Result<String, Error> {
+        let path = self.checked_path(name)?;
+        Ok(std::fs::read_to_string(path)?)
+    }
+
+    /// Save a prompt file's text, replacing any existing prompt of the same name
+    pub(crate) fn save(&self, name: &str, text: &str) -> Result<(), Error> {
+        validate_name(name)?;
+
+        let size = text.len() as u64;
+        if size > MAX_PROMPT_SIZE {
+            return Err(Error::TooLarge(name.to_string(), size));
+        }
+
+        /* Never write through a symlink, which could lead anywhere */
+        let path = self.root.join(format!("{name}.{EXTENSION}"));
+        if path.is_symlink() {
+            return Err(Error::InvalidName(name.to_string()));
+        }
+
+        std::fs::write(path, text)?;
+        Ok(())
+    }
+
+    pub(crate) fn delete(&self, name: &str) -> Result<(), Error> {
+        let path = self.path(name)?;
+        std::fs::remove_file(path)?;
+        Ok(())
+    }
+
+    /// Latest modification time of anything under the store's directory, including the
+    /// directories in it such as the action prompts, or of its fragments
+    pub(crate) fn modified(&self) -> Result<SystemTime, Error> {
+        let mut latest = tree_modified(&self.root)?;
+        if let Some(fragments) = self.fragments() {
+            latest = latest.max(fragments.modified()?);
+        }
+        Ok(latest)
+    }
+
+    /// Names of all the prompts in the store; files with invalid names are left out
+    pub(crate) fn names(&self) -> Result<Vec<String>, Error> {
+        let mut names = Vec::new();
+        for entry in std::fs::read_dir(&self.root)? {
+            let path = entry?.path();
+            if path.extension().is_none_or(|ext| ext != EXTENSION) {
+                continue;
+            }
+            let Some(name) = path.file_stem().and_then(|s| s.to_str()) else {
+                continue;
+            };
+            if validate_name(name).is_ok() {
+                names.push(name.to_string());
+            }
+        }
+        names.sort();
+        Ok(names)
+    }
+}
+
+/// Latest modification time of a directory or anything under it
+fn tree_modified(dir: &Path) -> Result<SystemTime, Error> {
+    let mut latest = std::fs::metadata(dir)?.modified()?;
+    for entry in std::fs::read_dir(dir)? {
+        let entry = entry?;
+        /* Don't follow symlinks out of the tree */
+        let modified = if entry.file_type()?.is_dir() {
+            tree_modified(&entry.path())?
+        } else {
+            entry.metadata()?.modified()?
+        };
+        latest = latest.max(modified);
+    }
+    Ok(latest)
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use crate::backend::GenerationOptions;
+
+    #[test]
+    fn test_validate_name() {
+        assert!(validate_name("default").is_ok());
+        assert!(validate_name("my-prompt_2").is_ok());
+        assert!(validate_name("").is_err());
+        assert!(validate_name("../../etc/passwd").is_err());
+        assert!(validate_name("sub/dir").is_err());
+        assert!(validate_name("dot.txt").is_err());
+        assert!(validate_name(&"x".repeat(65)).is_err());
+    }
+
+    #[test]
+    fn test_load() {
+        let store = PromptStore::open("prompts").unwrap().with_fragments(PromptStore::open("prompts/fragments").ok());
+
+        let prompt = store.load("default").unwrap();
+        assert_eq!("prompts/default.txt", prompt.filename);
+
+        assert!(matches!(store.load("../Cargo"), Err(Error::InvalidName(_))));
+        assert!(matches!(store.load("nonexistent"), Err(Error::NotFound(_))));
+        assert!(store.names().unwrap().contains(&"about".to_string()));
+    }
+
+    #[test]
+    fn test_save_and_delete() {
+        let dir = std::env::temp_dir().join(format!("clutha-store-test-{}", std::process::id()));
+        let store = PromptStore::create(&dir).unwrap();
+
+        store.save("test", "You are a test.\n---\n> Hi\n").unwrap();
+        assert_eq!(vec!["test"], store.names().unwrap());
+        assert_eq!("user", store.load("test").unwrap().initial.parts[0].role);
+
+        store.save("settings", "+++\nbackend: gemini\nmodel: models/expensive\nmax_output_tokens: 100000\n+++\nHi\n").unwrap();
+        let metadata = store.load("settings").unwrap().metadata;
+        assert_eq!(Some("models/expensive"), metadata.generation.model.as_deref());
+        let metadata = store.clone().without_backend_settings().load("settings").unwrap().metadata;
+        assert_eq!(None, metadata.backend);
+        assert_eq!(GenerationOptions::default(), metadata.generation);
+        store.delete("settings").unwrap();
+
+        assert!(matches!(store.save("../escape", "x"), Err(Error::InvalidName(_))));
+        let too_big = "x".repeat(MAX_PROMPT_SIZE as usize + 1);
+        assert!(matches!(store.save("big", &too_big), Err(Error::TooLarge(_, _))));
+
+        store.delete("test").unwrap();
+        assert!(matches!(store.delete("test"), Err(Error::NotFound(_))));
+        assert!(store.names().unwrap().is_empty());
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+
+    #[test]
+    fn test_modified() {
+        let dir = std::env::temp_dir().join(format!("clutha-store-modified-test-{}", std::process::id()));
+        let store = PromptStore::create(&dir).unwrap();
+        std::fs::create_dir(dir.join("actions")).unwrap();
+        let action = std::fs::File::create(dir.join("actions").join("ask.txt")).unwrap();
+        let before = store.modified().unwrap();
+
+        /* A change in a directory in the store counts */
+        action.set_modified(before + std::time::Duration::from_secs(60)).unwrap();
+        assert!(store.modified().unwrap() > before);
+
+        std::fs::remove_dir_all(dir).unwrap();
+    }
+}
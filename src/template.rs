@@ -0,0 +1,88 @@
+/// Values for the variables that can be used in prompts, as `{{name}}`
+#[derive(Clone, Debug, Default)]
+pub(crate) struct Variables {
+    /// The bot's display name
+    pub(crate) bot_name: String,
+    /// Display name of the user being responded to
+    pub(crate) user: String,
+    pub(crate) channel: String,
+    pub(crate) guild: String,
+    /// Current date and time
+    pub(crate) now: String,
+}
+
+pub(crate) const VARIABLE_NAMES: &[&str] = &["bot_name", "user", "channel", "guild", "now"];
+
+impl Variables {
+    fn get(&self, name: &str) -> Option<&str> {
+        let value = match name {
+            "bot_name" => &self.bot_name,
+            "user" => &self.user,
+            "channel" => &self.channel,
+            "guild" => &self.guild,
+            "now" => &self.now,
+            _ => return None,
+        };
+        Some(value)
+    }
+}
+
+/// Find each `{{name}}` placeholder in the text, returning its start and end positions and the name
+fn placeholders(text: &str) -> impl Iterator<Item = (usize, usize, &str)> {
+    let mut pos = 0;
+    std::iter::from_fn(move || {
+        let start = pos + text[pos..].find("{{")?;
+        let end = start + text[start..].find("}}")? + 2;
+        pos = end;
+        Some((start, end, text[start + 2..end - 2].trim()))
+    })
+}
+
+/// Replace placeholders with their values; unknown ones are left as they are
+pub(crate) fn expand(text: &str, variables: &Variables) -> String {
+    let mut result = String::with_capacity(text.len());
+    let mut pos = 0;
+
+    for (start, end, name) in placeholders(text) {
+        result.push_str(&text[pos..start]);
+        result.push_str(variables.get(name).unwrap_or(&text[start..end]));
+        pos = end;
+    }
+    result.push_str(&text[pos..]);
+
+    result
+}
+
+/// Names of placeholders in the text that aren't known variables
+pub(crate) fn unknown_variables(text: &str) -> Vec<String> {
+    placeholders(text)
+        .map(|(_, _, name)| name)
+        .filter(|name| !VARIABLE_NAMES.contains(name))
+        .map(str::to_string)
+        .collect()
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn test_expand() {
+        let variables = Variables {
+            bot_name: "Clutha".to_string(),
+            user: "Bob".to_string(),
+            ..Variables::default()
+        };
+
+        assert_eq!("You are Clutha, talking to Bob.", expand("You are {{bot_name}}, talking to {{ user }}.", &variables));
+        assert_eq!("Hello {{nobody}}!", expand("Hello {{nobody}}!", &variables));
+        assert_eq!("no placeholders", expand("no placeholders", &variables));
+        assert_eq!("unclosed {{user", expand("unclosed {{user", &variables));
+    }
+
+    #[test]
+    fn test_unknown_variables() {
+        assert!(unknown_variables("{{bot_name}} in {{channel}} on {{guild}} at {{now}}").is_empty());
+        assert_eq!(vec!["colour", "size"], unknown_variables("{{colour}} {{user}} {{size}}"));
+    }
+}